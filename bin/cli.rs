@@ -1,19 +1,25 @@
 use bytes::Bytes;
-use hashbrown::HashMap;
 use kek::{
-  client::Client, configuration::Configuration, message::ClientRequest, network::ConnectionTable,
-  operation::Operation, take_two, types::ReplicaID,
+  client::Client,
+  configuration::{Configuration, Timeouts},
+  journal::Journal,
+  message::{ClientRequest, Reply},
+  message_bus::MessageBus,
+  operation::{OpResult, Operation},
+  replica::Replica,
+  simulator::{Options, Simulator},
+  types::{ClusterID, ReplicaID},
 };
 use log::{debug, info};
 use std::{
   io::{stdin, stdout, Write},
   net::SocketAddr,
-  sync::{Arc, Mutex},
+  path::PathBuf,
   time::Duration,
 };
 use tokio::time::sleep;
 
-use clap::{Arg, Command};
+use clap::{Arg, ArgAction, Command};
 
 fn parse_command(input: &str, state: &Client) -> Option<ClientRequest> {
   let parts: Vec<&str> = input.split_whitespace().collect();
@@ -41,6 +47,25 @@ fn parse_command(input: &str, state: &Client) -> Option<ClientRequest> {
       command.op = Operation::Remove { key };
       Some(command)
     }
+    ["Get", key] => {
+      let key = Bytes::from(key.to_string());
+      command.op = Operation::Get { key };
+      Some(command)
+    }
+    ["GetStale", key] => {
+      let key = Bytes::from(key.to_string());
+      command.op = Operation::GetStale {
+        key,
+        min_op_number: state.last_op_number,
+      };
+      Some(command)
+    }
+    ["GetStale", key, min_op_number] => {
+      let key = Bytes::from(key.to_string());
+      let min_op_number = min_op_number.parse().ok()?;
+      command.op = Operation::GetStale { key, min_op_number };
+      Some(command)
+    }
     _ => None,
   }
 }
@@ -56,11 +81,57 @@ fn get_command(state: &Client) -> Option<ClientRequest> {
   }
 }
 
-async fn start_client_with_stdin(saddr: SocketAddr) {
+fn render_reply(reply: &Reply) {
+  let rendered = match &reply.result {
+    OpResult::AddResult(Ok(()))
+    | OpResult::UpdateResult(Ok(()))
+    | OpResult::RemoveResult(Ok(())) => "OK".to_string(),
+    OpResult::GetResult(Ok(value)) => {
+      format!(
+        "{} (op {})",
+        String::from_utf8_lossy(value),
+        reply.op_number
+      )
+    }
+    OpResult::JoinResult(Ok(op_number)) => format!("Joined at op {}", op_number),
+    OpResult::AddResult(Err(e))
+    | OpResult::UpdateResult(Err(e))
+    | OpResult::RemoveResult(Err(e))
+    | OpResult::GetResult(Err(e))
+    | OpResult::JoinResult(Err(e)) => format!("Error: {}", e),
+    OpResult::Rejected(e) => format!("Rejected: {} (view {})", e, reply.view_number),
+    OpResult::Outdated => "Outdated".to_string(),
+  };
+  println!("{}", rendered);
+}
+
+async fn start_client_with_stdin(saddr: SocketAddr, cluster_id: ClusterID) {
   info!("Client started, enter commands:");
 
   sleep(Duration::from_millis(10)).await;
-  Client::start(saddr, get_command).await;
+  Client::start(saddr, cluster_id, get_command, render_reply).await;
+}
+
+fn cluster_arg() -> Arg {
+  Arg::new("cluster")
+    .long("cluster")
+    .default_value("0")
+    .help("Cluster ID, replicas refuse connections from other clusters")
+}
+
+fn ticks_arg(name: &'static str, help: &'static str) -> Arg {
+  Arg::new(name)
+    .long(name)
+    .value_parser(clap::value_parser!(usize))
+    .help(help)
+}
+
+fn cluster_id(matches: &clap::ArgMatches) -> ClusterID {
+  matches
+    .get_one::<String>("cluster")
+    .unwrap()
+    .parse()
+    .expect("cluster ID")
 }
 
 #[tokio::main]
@@ -71,12 +142,15 @@ async fn main() {
     .about("Client or Replica node")
     .arg_required_else_help(true)
     .subcommand(
-      Command::new("run-client").about("Run a client").arg(
-        Arg::new("primary")
-          .long("primary")
-          .required(true)
-          .help("Address to primary"),
-      ),
+      Command::new("run-client")
+        .about("Run a client")
+        .arg(
+          Arg::new("primary")
+            .long("primary")
+            .required(true)
+            .help("Address to primary"),
+        )
+        .arg(cluster_arg()),
     )
     .subcommand(
       Command::new("run-replica")
@@ -92,6 +166,71 @@ async fn main() {
             .long("replica")
             .required(true)
             .help("This replica's index into addresses"),
+        )
+        .arg(
+          Arg::new("recover")
+            .long("recover")
+            .action(ArgAction::SetTrue)
+            .help("Restarting after a crash, recover state from the other replicas"),
+        )
+        .arg(
+          Arg::new("journal")
+            .long("journal")
+            .help("Path to the write-ahead log, defaults to replica_<replica>.journal"),
+        )
+        .arg(cluster_arg())
+        .arg(ticks_arg(
+          "heartbeat-ticks",
+          "Ticks after which an idle primary sends a heartbeat",
+        ))
+        .arg(ticks_arg(
+          "view-change-ticks",
+          "Ticks without hearing from the primary after which a backup starts a view change",
+        ))
+        .arg(ticks_arg(
+          "prepare-retransmit-ticks",
+          "Ticks after which the primary resends unacknowledged prepares",
+        ))
+        .arg(ticks_arg(
+          "recovery-ticks",
+          "Ticks a recovering replica waits for responses before asking again",
+        ))
+        .arg(ticks_arg(
+          "lease-ticks",
+          "Ticks a backup promises not to elect a new primary for, which lets the primary serve reads",
+        ))
+        .arg(ticks_arg(
+          "lease-margin-ticks",
+          "Ticks before the end of its lease at which the primary stops serving reads",
+        ))
+        .arg(
+          Arg::new("checkpoint-ops")
+            .long("checkpoint-ops")
+            .value_parser(clap::value_parser!(usize))
+            .help("Committed ops between checkpoints"),
+        ),
+    )
+    .subcommand(
+      Command::new("simulate")
+        .about("Run a simulated cluster with injected faults, checking its invariants")
+        .arg(
+          Arg::new("seed")
+            .long("seed")
+            .value_parser(clap::value_parser!(u64))
+            .help("Seed of the first run, random if not given"),
+        )
+        .arg(
+          Arg::new("runs")
+            .long("runs")
+            .value_parser(clap::value_parser!(u64))
+            .default_value("1")
+            .help("Number of runs, with consecutive seeds"),
+        )
+        .arg(
+          Arg::new("steps")
+            .long("steps")
+            .value_parser(clap::value_parser!(u64))
+            .help("Ticks with faults per run"),
         ),
     )
     .get_matches();
@@ -99,7 +238,7 @@ async fn main() {
   if let Some(client_matches) = matches.subcommand_matches("run-client") {
     let primary = client_matches.get_one::<String>("primary").unwrap();
     let primary_sockaddr: SocketAddr = primary.parse().expect("SocketAddr");
-    start_client_with_stdin(primary_sockaddr).await;
+    start_client_with_stdin(primary_sockaddr, cluster_id(client_matches)).await;
   } else if let Some(replica_matches) = matches.subcommand_matches("run-replica") {
     let addrs = replica_matches.get_one::<String>("addresses").unwrap();
     let replica_id: ReplicaID = replica_matches
@@ -108,15 +247,76 @@ async fn main() {
       .parse()
       .unwrap();
     let seperated = String::as_str(addrs).split(',').collect();
-    let conf = Configuration::new(seperated);
-    let addr = conf.find_addr(replica_id);
-    let _: ConnectionTable = Arc::new(Mutex::new(HashMap::new()));
+    let mut conf = Configuration::new(seperated);
+    conf.cluster_id = cluster_id(replica_matches);
+    let defaults = Timeouts::default();
+    let ticks = |name, default| {
+      replica_matches
+        .get_one::<usize>(name)
+        .copied()
+        .unwrap_or(default)
+    };
+    conf.timeouts = Timeouts {
+      heartbeat: ticks("heartbeat-ticks", defaults.heartbeat),
+      view_change: ticks("view-change-ticks", defaults.view_change),
+      prepare_retransmit: ticks("prepare-retransmit-ticks", defaults.prepare_retransmit),
+      recovery: ticks("recovery-ticks", defaults.recovery),
+      lease: ticks("lease-ticks", defaults.lease),
+      lease_margin: ticks("lease-margin-ticks", defaults.lease_margin),
+    };
+    if let Some(&checkpoint_ops) = replica_matches.get_one::<usize>("checkpoint-ops") {
+      assert!(checkpoint_ops > 0, "--checkpoint-ops must be positive");
+      conf.checkpoint_ops = checkpoint_ops;
+    }
+    if let Err(e) = conf.timeouts.validate() {
+      panic!("Invalid timeouts: {}", e);
+    }
+
+    let journal_path = replica_matches
+      .get_one::<String>("journal")
+      .map(PathBuf::from)
+      .unwrap_or_else(|| PathBuf::from(format!("replica_{}.journal", replica_id)));
+    let (journal, replayed) = Journal::open(&journal_path).expect("open journal");
 
-    debug!("Starting replica {:?}", addr.clone());
-    let replica = Replica::new(conf, replica_id, clients);
-    let mut server = take_two::Server::new(addr, replica);
-    server.run().unwrap();
+    debug!(
+      "Starting replica {} at {:?}",
+      replica_id,
+      conf.find_addr(replica_id)
+    );
+    let replica = if replica_matches.get_flag("recover") {
+      Replica::recovering(conf.clone(), replica_id, uuid::Uuid::new_v4().as_u128())
+    } else if !replayed.is_empty() {
+      Replica::restart(conf.clone(), replica_id, replayed)
+    } else {
+      Replica::new(conf.clone(), replica_id)
+    };
+    let mut bus = MessageBus::new(conf, replica_id, replica, journal);
+    bus.run().unwrap();
 
     // start_io_layer(replica, addr).await;
+  } else if let Some(sim_matches) = matches.subcommand_matches("simulate") {
+    simulate(sim_matches);
+  }
+}
+
+fn simulate(matches: &clap::ArgMatches) {
+  let seed = matches
+    .get_one::<u64>("seed")
+    .copied()
+    .unwrap_or_else(|| uuid::Uuid::new_v4().as_u64_pair().0);
+  let runs = *matches.get_one::<u64>("runs").unwrap();
+  let mut options = Options::default();
+  if let Some(&steps) = matches.get_one::<u64>("steps") {
+    options.steps = steps;
+  }
+
+  for seed in seed..seed + runs {
+    match Simulator::new(seed, options.clone()).run() {
+      Ok(summary) => println!("seed {}: {:?}", seed, summary),
+      Err(violation) => {
+        println!("seed {}: {}", seed, violation);
+        std::process::exit(1);
+      }
+    }
   }
 }
@@ -0,0 +1,12 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{client_table::ClienTable, kvstore::KVStore, types::OpNumber};
+
+/// The state after executing every op up to and including `op_number`, it replaces that prefix
+/// of the log.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checkpoint {
+  pub op_number: OpNumber,
+  pub store: KVStore,
+  pub client_table: ClienTable,
+}
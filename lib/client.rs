@@ -1,32 +1,40 @@
-use std::{
-  io::Read,
-  net::{SocketAddr, TcpStream},
-};
+use std::net::{SocketAddr, TcpStream};
 
 use crate::{
-  message::{self, IOMessage},
+  message::{self, Handshake, IOMessage, Identity},
   network,
 };
-use log::debug;
+use log::{debug, warn};
 
-use crate::types::{ClientID, RequestID};
+use crate::{
+  operation::OpResult,
+  types::{ClientID, ClusterID, OpNumber, RequestID},
+};
 
 pub struct Client {
   pub client_id: ClientID,
   pub request_number: RequestID,
+  pub last_op_number: OpNumber, // latest op observed through a reply, for read-your-writes
 }
 
 impl Client {
-  pub async fn start<F>(s: SocketAddr, f: F)
+  pub async fn start<F, G>(s: SocketAddr, cluster_id: ClusterID, f: F, on_reply: G)
   where
     F: Fn(&Self) -> Option<message::ClientRequest>,
+    G: Fn(&message::Reply),
   {
-    let client = Client {
+    let mut client = Client {
       // TODO: This should be generated from a seed for testing.
       client_id: uuid::Uuid::new_v4().as_u128(),
       request_number: 0,
+      last_op_number: 0,
     };
     let mut connection = TcpStream::connect(s).unwrap();
+    let handshake = Handshake {
+      cluster_id,
+      peer: Identity::Client(client.client_id),
+    };
+    network::write_message(&mut connection, &IOMessage::Handshake(handshake)).unwrap();
 
     loop {
       match f(&client) {
@@ -35,35 +43,24 @@ impl Client {
           network::write_message(&mut connection, &IOMessage::Client(request)).unwrap();
           debug!("Sent");
 
-          // let frame = framed.next().await.unwrap().unwrap();
-          // let resp: Reply = bincode::deserialize(&frame).unwrap();
-          // connection.
-          // match network::read_message(&mut connection).unwrap() {
-          //   IOMessage::Client(client_request) => debug!("{:?}", client_request),
-          //   IOMessage::Replica(_) => todo!(),
-          //   IOMessage::Reply(reply) => debug!("{:?}", reply),
-          // Some(Ok(frame)) => {
-          //   debug!("Received frame: {:?}", frame);
-          //   match bincode::deserialize::<Reply>(&frame) {
-          //     Ok(resp) => {
-          //       debug!("{:?}", resp);
-          //     }
-          //     Err(e) => {
-          //       warn!("Failed to deserialize frame: {:?}", e);
-          //       todo!()
-          //     }
-          //   }
-          // }
-          // None => {
-          //   warn!("Connection closed by server");
-          //   todo!()
-          // }
-          // Some(Err(e)) => {
-          //   warn!("Error receiving frame: {:?}", e);
-          //   todo!()
-          // }
-          // }
-          // debug!("{:?}", resp);
+          // At most one request is in flight, anything else on the connection is stale.
+          loop {
+            match network::recv_message(&mut connection) {
+              Ok(IOMessage::Reply(reply)) if reply.request_number == client.request_number => {
+                if !matches!(reply.result, OpResult::Rejected(_)) {
+                  client.last_op_number = client.last_op_number.max(reply.op_number);
+                }
+                on_reply(&reply);
+                break;
+              }
+              Ok(msg) => debug!("Ignoring {:?}", msg),
+              Err(e) => {
+                warn!("Connection to {} failed: {}", s, e);
+                return;
+              }
+            }
+          }
+          client.request_number += 1;
         }
       }
     }
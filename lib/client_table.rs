@@ -1,52 +1,67 @@
 use hashbrown::HashMap;
+use serde::{Deserialize, Serialize};
 
 use crate::{
   operation::OpResult,
-  types::{ClientID, RequestID},
+  types::{ClientID, OpNumber, RequestID},
 };
 
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Entry {
   pub last_request_id: RequestID,
+  pub last_op_number: OpNumber,
   pub last_result: Option<OpResult>, // None implies not executed
 }
 
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ClienTable {
   table: HashMap<ClientID, Entry>,
 }
 
+/// Only tracks executed requests, which makes the table a function of the committed prefix of
+/// the log and keeps it identical on every replica. Requests that are still being prepared are
+/// found in the log itself.
 impl ClienTable {
-  pub fn insert(&mut self, client_id: ClientID, request: RequestID) {
-    self.table.insert(
-      client_id,
-      Entry {
-        last_request_id: request,
-        last_result: None,
-      },
-    );
+  pub fn find_client(&self, id: ClientID) -> Option<&Entry> {
+    self.table.get(&id)
   }
 
-  // pub fn add_client(&mut self) -> ClientID {
-  //   let new_client = self.table.iter().max_by_key(|k| k.0).map_or(1, |k| k.0 + 1);
-  //   self.table.insert(new_client, Entry::default());
-  //   new_client
-  // }
+  /// Caches the result of an executed request, unless the client has since moved on to a newer
+  /// request.
+  pub fn update_client(
+    &mut self,
+    id: ClientID,
+    request: RequestID,
+    op_number: OpNumber,
+    result: OpResult,
+  ) {
+    let entry = self.table.entry(id).or_default();
+    if request >= entry.last_request_id {
+      entry.last_request_id = request;
+      entry.last_op_number = op_number;
+      entry.last_result = Some(result);
+    }
+  }
+
+  pub fn len(&self) -> usize {
+    self.table.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.table.is_empty()
+  }
 
-  // pub fn find_client(&self, id: ClientID) -> Option<&Entry> {
-  //   self.table.get(&id)
-  // }
+  pub fn iter(&self) -> impl Iterator<Item = (&ClientID, &Entry)> {
+    self.table.iter()
+  }
+}
 
-  // pub fn update_client(&mut self, id: ClientID, op_number: OpNumber, result: Option<OpResult>) {
-  //   let update = self.table.insert(
-  //     id,
-  //     Entry {
-  //       last_request_id: op_number,
-  //       last_result: result,
-  //     },
-  //   );
-  //   assert!(update.is_some());
-  // }
+impl FromIterator<(ClientID, Entry)> for ClienTable {
+  fn from_iter<I: IntoIterator<Item = (ClientID, Entry)>>(iter: I) -> Self {
+    ClienTable {
+      table: iter.into_iter().collect(),
+    }
+  }
 }
 
 // #[cfg(test)]
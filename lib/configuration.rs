@@ -1,10 +1,84 @@
 use std::net::SocketAddr;
 
-use crate::types::{ReplicaID, ViewNumber};
+use crate::types::{ClusterID, ReplicaID, Timestamp, ViewNumber};
 
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug)]
 pub struct Configuration {
+  pub cluster_id: ClusterID, // connections from other clusters are refused
   pub replicas: Vec<SocketAddr>,
+  pub timeouts: Timeouts,
+  /// Committed ops between checkpoints, the log is discarded up to the latest checkpoint.
+  pub checkpoint_ops: usize,
+}
+
+impl Default for Configuration {
+  fn default() -> Self {
+    Configuration {
+      cluster_id: ClusterID::default(),
+      replicas: Vec::new(),
+      timeouts: Timeouts::default(),
+      checkpoint_ops: 1024,
+    }
+  }
+}
+
+/// Difference in tick rate between replicas, in percent, that the lease margin must cover. Ticks
+/// follow the clock, so this absorbs clock drift and an event loop catching up after a stall.
+pub const TICK_SKEW_PERCENT: Timestamp = 10;
+
+/// Timeouts of a replica, counted in calls to `Replica::tick`.
+#[derive(Clone, Debug)]
+pub struct Timeouts {
+  /// Ticks without a Prepare or Commit after which an idle primary sends a Commit heartbeat.
+  pub heartbeat: usize,
+  /// Ticks without hearing from the primary, or without completing a view change, after which a
+  /// backup moves on to the next view.
+  pub view_change: usize,
+  /// Ticks without progress after which the primary resends the Prepares a backup has not
+  /// acknowledged.
+  pub prepare_retransmit: usize,
+  /// Ticks a recovering replica waits for RecoveryResponses before asking again.
+  pub recovery: usize,
+  /// Ticks a backup promises not to help elect a new primary for, after acknowledging a Prepare
+  /// or Commit. Must be shorter than `view_change` so view changes are delayed, not stalled.
+  pub lease: Timestamp,
+  /// The primary gives up its read lease this many ticks before the backups consider it expired,
+  /// which covers the difference in tick rates between replicas.
+  pub lease_margin: Timestamp,
+}
+
+impl Default for Timeouts {
+  fn default() -> Self {
+    Timeouts {
+      heartbeat: 100,
+      view_change: 500,
+      prepare_retransmit: 50,
+      recovery: 200,
+      lease: 400,
+      lease_margin: 100,
+    }
+  }
+}
+
+impl Timeouts {
+  pub fn validate(&self) -> Result<(), String> {
+    if self.heartbeat >= self.view_change {
+      return Err("heartbeats must be sent more often than backups time out".to_string());
+    }
+    if self.lease >= self.view_change {
+      return Err("leases must expire before backups time out".to_string());
+    }
+    if self.lease_margin >= self.lease {
+      return Err("the lease margin must be shorter than the lease".to_string());
+    }
+    if self.lease_margin * 100 < self.lease * TICK_SKEW_PERCENT {
+      return Err(format!(
+        "the lease margin must be at least {}% of the lease to cover the tick skew",
+        TICK_SKEW_PERCENT
+      ));
+    }
+    Ok(())
+  }
 }
 
 impl Configuration {
@@ -53,6 +127,11 @@ impl Configuration {
   pub fn primary_id(&self, view_number: ViewNumber) -> ReplicaID {
     view_number % self.replicas.len()
   }
+
+  /// Number of replicas (including the primary) that must agree, f + 1.
+  pub fn quorum(&self) -> usize {
+    self.replicas.len() / 2 + 1
+  }
 }
 
 // #[cfg(test)]
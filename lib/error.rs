@@ -0,0 +1,32 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// Why a request failed or was not executed at all, sent back to the client in the Reply.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum OpError {
+  NotFound,
+  KeyExists,
+  KeyTooLarge,
+  ValueTooLarge,
+  PreconditionFailed, // a condition attached to the request does not hold
+  NotPrimary,         // the primary is given by the view number of the reply
+  NotNormal,          // view change or recovery in progress
+  QueueFull,
+}
+
+impl fmt::Display for OpError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let msg = match self {
+      OpError::NotFound => "key not found",
+      OpError::KeyExists => "key already exists",
+      OpError::KeyTooLarge => "key too large",
+      OpError::ValueTooLarge => "value too large",
+      OpError::PreconditionFailed => "precondition failed",
+      OpError::NotPrimary => "replica is not the primary",
+      OpError::NotNormal => "replica is changing view or recovering",
+      OpError::QueueFull => "request queue is full",
+    };
+    f.write_str(msg)
+  }
+}
@@ -0,0 +1,429 @@
+use std::{
+  fs::{self, File, OpenOptions},
+  io::{self, Error, ErrorKind, Read, Seek, SeekFrom, Write},
+  os::fd::{AsRawFd, RawFd},
+  path::{Path, PathBuf},
+};
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  checkpoint::Checkpoint,
+  log::Log,
+  message::ClientRequest,
+  snapshot,
+  types::{OpNumber, ViewNumber},
+  utils::{crc32, sync_dir},
+};
+
+/// Size of the length and checksum that precede every record.
+const HEADER_SIZE: usize = 8;
+
+/// The journal is an append-only file of records, replaying them in order rebuilds the log.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Record {
+  /// Normal operation started in `view_number`, with every entry after `op_number` dropped. A
+  /// log that ends before `op_number` continues after it, a checkpoint covers the ops between.
+  View {
+    view_number: ViewNumber,
+    op_number: OpNumber,
+  },
+  Entry {
+    view_number: ViewNumber,
+    op_number: OpNumber,
+    request: ClientRequest,
+  },
+  /// A view change to `view_number` started, the replica takes no part in earlier views anymore.
+  ViewChange { view_number: ViewNumber },
+}
+
+/// Frames a record as `[length: u32][crc32: u32][bincode record]`, both big endian.
+pub fn encode(record: &Record) -> Vec<u8> {
+  let payload = bincode::serialize(record).expect("record serializes");
+  let mut buf = Vec::with_capacity(HEADER_SIZE + payload.len());
+  buf.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+  buf.extend_from_slice(&crc32(&payload).to_be_bytes());
+  buf.extend_from_slice(&payload);
+  buf
+}
+
+/// Decodes the record at the start of `buf` along with its framed size. Returns None for a
+/// partial or corrupt record, which is what a torn write at the end of the file looks like.
+pub fn decode(buf: &[u8]) -> Option<(Record, usize)> {
+  let header = buf.get(..HEADER_SIZE)?;
+  let len = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+  let checksum = u32::from_be_bytes(header[4..].try_into().unwrap());
+  let payload = buf.get(HEADER_SIZE..HEADER_SIZE + len)?;
+  if crc32(payload) != checksum {
+    return None;
+  }
+  let record = bincode::deserialize(payload).ok()?;
+  Some((record, HEADER_SIZE + len))
+}
+
+/// Whether the record at the start of `buf` is the last one, only that one can be torn by a
+/// crash while it was written.
+fn is_tail(buf: &[u8]) -> bool {
+  let Some(header) = buf.get(..HEADER_SIZE) else {
+    return true;
+  };
+  let len = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+  HEADER_SIZE + len >= buf.len()
+}
+
+/// The state recovered from a journal and its snapshot on startup.
+#[derive(Clone, Debug, Default)]
+pub struct Replayed {
+  pub log: Log,
+  pub last_normal_view: ViewNumber,
+  pub view: ViewNumber, // latest view we took part in, normal or not
+  pub checkpoint: Checkpoint,
+}
+
+impl Replayed {
+  /// Nothing was journaled, not even a view change, the replica starts out fresh.
+  pub fn is_empty(&self) -> bool {
+    self.log.last_op_number() == 0 && self.view == 0
+  }
+
+  /// The log only continues after the checkpoint, the entries it covers are dropped.
+  pub fn install_checkpoint(&mut self, view: ViewNumber, checkpoint: Checkpoint) {
+    self.log.compact(checkpoint.op_number);
+    self.last_normal_view = self.last_normal_view.max(view);
+    self.view = self.view.max(view);
+    self.checkpoint = checkpoint;
+  }
+
+  pub fn apply(&mut self, record: Record) {
+    match record {
+      Record::View {
+        view_number,
+        op_number,
+      } => {
+        if op_number > self.log.last_op_number() {
+          self.log.compact(op_number);
+        }
+        self.log.truncate(op_number);
+        self.last_normal_view = view_number;
+        self.view = self.view.max(view_number);
+      }
+      Record::Entry {
+        view_number,
+        op_number,
+        request,
+      } => {
+        if op_number > self.log.last_op_number() + 1 {
+          // The ops skipped over were installed from a checkpoint instead.
+          self.log.compact(op_number - 1);
+        }
+        self.log.truncate(op_number - 1);
+        self.log.append(view_number, request);
+        self.last_normal_view = self.last_normal_view.max(view_number);
+        self.view = self.view.max(view_number);
+      }
+      Record::ViewChange { view_number } => self.view = self.view.max(view_number),
+    }
+  }
+}
+
+/// The journal along with the snapshot of the latest checkpoint, which is kept next to it with
+/// the extension `snapshot`. The journal only needs the records past the checkpoint.
+pub struct Journal {
+  path: PathBuf,
+  file: File,
+  len: u64, // where the next record goes
+}
+
+impl Journal {
+  /// Opens or creates the journal at `path` and replays it on top of the snapshot, if there is
+  /// one. A torn record at the end is cut off so that new records are appended after the last
+  /// intact one. A corrupt record with others behind it fails with `InvalidData`, those were
+  /// acknowledged already and must not be dropped.
+  pub fn open(path: &Path) -> Result<(Journal, Replayed), Error> {
+    let mut file = OpenOptions::new()
+      .read(true)
+      .append(true)
+      .create(true)
+      .open(path)?;
+    // Records synced to a journal we just created must not be lost along with its directory entry.
+    sync_dir(path)?;
+
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf)?;
+
+    let mut replayed = Replayed::default();
+    let mut pos = 0;
+    while let Some((record, size)) = decode(&buf[pos..]) {
+      replayed.apply(record);
+      pos += size;
+    }
+    if pos < buf.len() {
+      if !is_tail(&buf[pos..]) {
+        return Err(Error::new(
+          ErrorKind::InvalidData,
+          format!("corrupt journal record at byte {} of {:?}", pos, path),
+        ));
+      }
+      warn!(
+        "Dropping {} bytes of torn journal records in {:?}",
+        buf.len() - pos,
+        path
+      );
+      file.set_len(pos as u64)?;
+      file.seek(SeekFrom::End(0))?;
+    }
+
+    match snapshot::load(&snapshot_path(path)) {
+      Ok((view, checkpoint)) => replayed.install_checkpoint(view, checkpoint),
+      Err(e) if e.kind() == ErrorKind::NotFound => {}
+      Err(e) => return Err(e),
+    }
+
+    debug!(
+      "Replayed {:?} up to op {} in view {}",
+      path,
+      replayed.log.last_op_number(),
+      replayed.last_normal_view
+    );
+    Ok((
+      Journal {
+        path: path.to_path_buf(),
+        file,
+        len: pos as u64,
+      },
+      replayed,
+    ))
+  }
+
+  /// Appends the records and only returns once they are durable.
+  pub fn append(&mut self, records: &[Record]) -> io::Result<()> {
+    let mut buf = Vec::new();
+    for record in records {
+      buf.extend_from_slice(&encode(record));
+    }
+    self.file.write_all(&buf)?;
+    self.len += buf.len() as u64;
+    self.file.sync_data()
+  }
+
+  /// Where the snapshot of the latest checkpoint goes, it is saved with `snapshot::save`. The
+  /// journal still replays correctly on top of a new snapshot until it is rewritten.
+  pub fn snapshot_path(&self) -> PathBuf {
+    snapshot_path(&self.path)
+  }
+
+  /// Replaces the journal with `buf`, encoded records that continue from a snapshot that is
+  /// durable already. The replacement is atomic. Returns the previous journal file, which
+  /// writes still in flight may be using.
+  pub fn rewrite(&mut self, buf: &[u8]) -> io::Result<File> {
+    let tmp = self.path.with_extension("journal.tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(buf)?;
+    file.sync_all()?;
+    fs::rename(&tmp, &self.path)?;
+    sync_dir(&self.path)?;
+
+    debug!("Journal rewritten to {} bytes", buf.len());
+    let file = OpenOptions::new().append(true).open(&self.path)?;
+    self.len = buf.len() as u64;
+    Ok(std::mem::replace(&mut self.file, file))
+  }
+
+  /// Reserves room for `len` bytes of encoded records, for callers that do the write themselves.
+  /// Returns the file and the offset to write at, the records are durable once the write and a
+  /// following fdatasync completed.
+  pub fn reserve(&mut self, len: usize) -> (RawFd, u64) {
+    let offset = self.len;
+    self.len += len as u64;
+    (self.file.as_raw_fd(), offset)
+  }
+}
+
+fn snapshot_path(path: &Path) -> PathBuf {
+  path.with_extension("snapshot")
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{fs, path::PathBuf};
+
+  use super::*;
+  use crate::operation::Operation;
+
+  fn temp_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("{}-{}.journal", name, std::process::id()));
+    let _ = fs::remove_file(&path);
+    path
+  }
+
+  fn entry(view_number: ViewNumber, op_number: OpNumber, request_number: usize) -> Record {
+    Record::Entry {
+      view_number,
+      op_number,
+      request: ClientRequest {
+        client_id: 1,
+        request_number,
+        op: Operation::Join,
+      },
+    }
+  }
+
+  #[test]
+  fn replay() {
+    let path = temp_path("replay");
+    let (mut journal, replayed) = Journal::open(&path).unwrap();
+    assert_eq!(replayed.log.last_op_number(), 0);
+    journal
+      .append(&[
+        entry(0, 1, 1),
+        entry(0, 2, 2),
+        // Op 2 did not survive the view change, a different op took its place.
+        Record::View {
+          view_number: 2,
+          op_number: 1,
+        },
+        entry(2, 2, 3),
+      ])
+      .unwrap();
+    drop(journal);
+
+    let (_, replayed) = Journal::open(&path).unwrap();
+    assert_eq!(replayed.last_normal_view, 2);
+    assert_eq!(replayed.log.last_op_number(), 2);
+    assert_eq!(replayed.log.get(1).unwrap().request_number, 1);
+    assert_eq!(replayed.log.get(2).unwrap().request_number, 3);
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn view_past_log() {
+    // A log installed from another replica that starts after a checkpoint we don't have.
+    let mut replayed = Replayed::default();
+    for record in [
+      entry(0, 1, 1),
+      entry(0, 2, 2),
+      Record::View {
+        view_number: 3,
+        op_number: 1,
+      },
+      Record::View {
+        view_number: 3,
+        op_number: 64,
+      },
+    ] {
+      replayed.apply(record);
+    }
+    assert_eq!(replayed.last_normal_view, 3);
+    assert_eq!(replayed.log.first_op_number(), 65);
+    assert_eq!(replayed.log.last_op_number(), 64);
+  }
+
+  #[test]
+  fn view_change() {
+    // A replica that sent a DoViewChange must not fall back to an earlier view after a restart.
+    let mut replayed = Replayed::default();
+    replayed.apply(entry(2, 1, 1));
+    replayed.apply(Record::ViewChange { view_number: 4 });
+    assert_eq!(replayed.last_normal_view, 2);
+    assert_eq!(replayed.view, 4);
+    assert!(!replayed.is_empty());
+  }
+
+  #[test]
+  fn torn_tail() {
+    let path = temp_path("torn-tail");
+    let (mut journal, _) = Journal::open(&path).unwrap();
+    journal.append(&[entry(0, 1, 1), entry(0, 2, 2)]).unwrap();
+    drop(journal);
+
+    // A record whose header made it to disk, but not all of its payload.
+    let torn = &encode(&entry(0, 3, 3))[..HEADER_SIZE + 2];
+    let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+    file.write_all(torn).unwrap();
+    drop(file);
+
+    let (mut journal, replayed) = Journal::open(&path).unwrap();
+    assert_eq!(replayed.log.last_op_number(), 2);
+    // New records go where the torn one was.
+    journal.append(&[entry(0, 3, 4)]).unwrap();
+    drop(journal);
+
+    let (_, replayed) = Journal::open(&path).unwrap();
+    assert_eq!(replayed.log.last_op_number(), 3);
+    assert_eq!(replayed.log.get(3).unwrap().request_number, 4);
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn corrupt_middle_record() {
+    let path = temp_path("corrupt-middle");
+    let (mut journal, _) = Journal::open(&path).unwrap();
+    journal
+      .append(&[entry(0, 1, 1), entry(0, 2, 2), entry(0, 3, 3)])
+      .unwrap();
+    drop(journal);
+
+    // Bit rot in the payload of the second record, the third is intact behind it.
+    let mut buf = fs::read(&path).unwrap();
+    let second = encode(&entry(0, 1, 1)).len();
+    buf[second + HEADER_SIZE] ^= 1;
+    fs::write(&path, &buf).unwrap();
+
+    let err = Journal::open(&path).err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    // Nothing was cut off.
+    assert_eq!(fs::read(&path).unwrap(), buf);
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn checkpoint() {
+    let path = temp_path("checkpoint");
+    let (mut journal, _) = Journal::open(&path).unwrap();
+    let records: Vec<Record> = (1..=4).map(|op| entry(1, op, op)).collect();
+    journal.append(&records).unwrap();
+
+    let checkpoint = Checkpoint {
+      op_number: 3,
+      ..Checkpoint::default()
+    };
+    let rewritten = [
+      Record::View {
+        view_number: 1,
+        op_number: 3,
+      },
+      entry(1, 4, 4),
+    ];
+    snapshot::save(&journal.snapshot_path(), 1, &checkpoint).unwrap();
+    let buf: Vec<u8> = rewritten.iter().flat_map(encode).collect();
+    journal.rewrite(&buf).unwrap();
+    journal.append(&[entry(1, 5, 5)]).unwrap();
+    drop(journal);
+
+    let (_, replayed) = Journal::open(&path).unwrap();
+    assert_eq!(replayed.checkpoint, checkpoint);
+    assert_eq!(replayed.last_normal_view, 1);
+    assert_eq!(replayed.log.first_op_number(), 4);
+    assert_eq!(replayed.log.last_op_number(), 5);
+    assert_eq!(
+      fs::metadata(&path).unwrap().len() as usize,
+      rewritten
+        .iter()
+        .chain([&entry(1, 5, 5)])
+        .map(|r| encode(r).len())
+        .sum()
+    );
+    fs::remove_file(&path).unwrap();
+    fs::remove_file(snapshot_path(&path)).unwrap();
+  }
+
+  #[test]
+  fn corrupt_record() {
+    let mut buf = encode(&entry(0, 1, 1));
+    assert!(decode(&buf).is_some());
+    let last = buf.len() - 1;
+    buf[last] ^= 1;
+    assert!(decode(&buf).is_none());
+  }
+}
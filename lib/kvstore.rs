@@ -1,28 +1,63 @@
 use bytes::Bytes;
-use hashbrown::HashMap;
+use hashbrown::{hash_map::Entry, HashMap};
+use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Debug, Default)]
+use crate::error::OpError;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub struct KVStore {
   store: HashMap<Bytes, Bytes>,
 }
 
 impl KVStore {
-  pub fn set(&mut self, k: Bytes, v: Bytes) {
-    let i = self.store.insert(k, v);
-    assert!(i.is_none())
+  pub fn add(&mut self, k: Bytes, v: Bytes) -> Result<(), OpError> {
+    match self.store.entry(k) {
+      Entry::Occupied(_) => Err(OpError::KeyExists),
+      Entry::Vacant(e) => {
+        e.insert(v);
+        Ok(())
+      }
+    }
+  }
+
+  pub fn update(&mut self, k: Bytes, v: Bytes) -> Result<(), OpError> {
+    match self.store.get_mut(&k) {
+      Some(old) => {
+        *old = v;
+        Ok(())
+      }
+      None => Err(OpError::NotFound),
+    }
+  }
+
+  pub fn get(&self, k: &Bytes) -> Option<&Bytes> {
+    self.store.get(k)
   }
 
-  pub fn get(&self, k: Bytes) -> Option<&Bytes> {
-    match self.store.get(&k) {
-      Some(v) => Some(v),
-      None => todo!(),
+  pub fn remove(&mut self, k: &Bytes) -> Result<(), OpError> {
+    match self.store.remove(k) {
+      Some(_) => Ok(()),
+      None => Err(OpError::NotFound),
     }
   }
 
-  pub fn remove(&mut self, k: Bytes) {
-    match self.store.remove(&k) {
-      Some(_) => (),
-      None => todo!(),
+  pub fn len(&self) -> usize {
+    self.store.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.store.is_empty()
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = (&Bytes, &Bytes)> {
+    self.store.iter()
+  }
+}
+
+impl FromIterator<(Bytes, Bytes)> for KVStore {
+  fn from_iter<I: IntoIterator<Item = (Bytes, Bytes)>>(iter: I) -> Self {
+    KVStore {
+      store: iter.into_iter().collect(),
     }
   }
 }
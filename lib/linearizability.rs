@@ -0,0 +1,301 @@
+use std::{collections::HashSet, fmt};
+
+use bytes::Bytes;
+use hashbrown::HashMap;
+
+use crate::{
+  error::OpError,
+  kvstore::KVStore,
+  operation::{OpResult, Operation},
+  types::ClientID,
+};
+
+// Checks that what the clients observed could have come from a single KVStore executing one op
+// at a time, each op taking effect somewhere between its invocation and its response. This is the
+// search of Wing & Gong, with the set of ops linearized so far and the resulting state memoized
+// as in Knossos. Ops on different keys don't affect each other, so every key is checked on its
+// own, which keeps the search small.
+//
+// Join and GetStale are not part of the model: the result of Join depends on the commit number
+// and GetStale may read stale state by design.
+
+/// One op as seen by a client. Without a result it might or might not have taken effect, the
+/// client gave up on it or the history ended first.
+#[derive(Clone, Debug)]
+pub struct Call {
+  pub client_id: ClientID,
+  pub op: Operation,
+  pub result: Option<OpResult>,
+  invoked: usize,
+  returned: usize, // usize::MAX without a result
+}
+
+/// Invocations and responses in the order the clients saw them.
+#[derive(Debug, Default)]
+pub struct History {
+  calls: Vec<Call>,
+  pending: HashMap<ClientID, usize>, // index into calls, a client has at most one op in flight
+  events: usize,
+}
+
+#[derive(Debug)]
+pub struct NotLinearizable {
+  pub key: Bytes,
+  pub calls: Vec<Call>, // on the key, in the order they were invoked
+}
+
+impl fmt::Display for NotLinearizable {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "the {} ops on key {:?} are not linearizable",
+      self.calls.len(),
+      self.key
+    )
+  }
+}
+
+impl History {
+  pub fn invoke(&mut self, client_id: ClientID, op: Operation) {
+    if key(&op).is_none() {
+      return;
+    }
+    // A client that starts a new op has given up on the previous one.
+    self.pending.insert(client_id, self.calls.len());
+    self.calls.push(Call {
+      client_id,
+      op,
+      result: None,
+      invoked: self.events,
+      returned: usize::MAX,
+    });
+    self.events += 1;
+  }
+
+  /// A rejected or outdated result says nothing about whether the op took effect, the op stays
+  /// pending.
+  pub fn respond(&mut self, client_id: ClientID, result: OpResult) {
+    if matches!(result, OpResult::Rejected(_) | OpResult::Outdated) {
+      return;
+    }
+    let Some(i) = self.pending.remove(&client_id) else {
+      return;
+    };
+    let call = &mut self.calls[i];
+    call.result = Some(result);
+    call.returned = self.events;
+    self.events += 1;
+  }
+
+  pub fn len(&self) -> usize {
+    self.calls.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.calls.is_empty()
+  }
+
+  pub fn check(&self) -> Result<(), NotLinearizable> {
+    let mut by_key: HashMap<&Bytes, Vec<&Call>> = HashMap::new();
+    for call in &self.calls {
+      let key = key(&call.op).expect("only modelled ops are recorded");
+      by_key.entry(key).or_default().push(call);
+    }
+    let mut keys: Vec<_> = by_key.keys().copied().collect();
+    keys.sort();
+    for key in keys {
+      let calls = &by_key[key];
+      if !linearizable(calls) {
+        return Err(NotLinearizable {
+          key: key.clone(),
+          calls: calls.iter().map(|&c| c.clone()).collect(),
+        });
+      }
+    }
+    Ok(())
+  }
+}
+
+fn key(op: &Operation) -> Option<&Bytes> {
+  match op {
+    Operation::Add { key, .. }
+    | Operation::Update { key, .. }
+    | Operation::Remove { key }
+    | Operation::Get { key } => Some(key),
+    Operation::GetStale { .. } | Operation::Join => None,
+  }
+}
+
+/// Runs `op` against the model, a KVStore holding at most the one key.
+fn apply(value: &Option<Bytes>, op: &Operation) -> (Option<Bytes>, OpResult) {
+  let mut store: KVStore = value
+    .iter()
+    .map(|v| (key(op).expect("modelled op").clone(), v.clone()))
+    .collect();
+  let result = match op.clone() {
+    Operation::Add { key, value } => OpResult::AddResult(store.add(key, value)),
+    Operation::Update { key, value } => OpResult::UpdateResult(store.update(key, value)),
+    Operation::Remove { key } => OpResult::RemoveResult(store.remove(&key)),
+    Operation::Get { key } => {
+      OpResult::GetResult(store.get(&key).cloned().ok_or(OpError::NotFound))
+    }
+    Operation::GetStale { .. } | Operation::Join => unreachable!("not modelled"),
+  };
+  let value = store.iter().next().map(|(_, v)| v.clone());
+  (value, result)
+}
+
+/// Depth first search for an order of `calls`, sorted by invocation, that respects real time and
+/// matches every result.
+fn linearizable(calls: &[&Call]) -> bool {
+  struct Frame {
+    value: Option<Bytes>,
+    done: Vec<bool>, // linearized so far
+    next: usize,     // candidate to try next
+  }
+
+  let mut seen: HashSet<(Vec<bool>, Option<Bytes>)> = HashSet::new();
+  let mut stack = vec![Frame {
+    value: None,
+    done: vec![false; calls.len()],
+    next: 0,
+  }];
+
+  while let Some(frame) = stack.last_mut() {
+    // Every completed op is linearized, the pending ones are free to never take effect.
+    if calls
+      .iter()
+      .zip(&frame.done)
+      .all(|(c, &done)| done || c.result.is_none())
+    {
+      return true;
+    }
+    // An op can only go next if it was invoked before every op still left had returned.
+    let horizon = calls
+      .iter()
+      .zip(&frame.done)
+      .filter(|(_, &done)| !done)
+      .map(|(c, _)| c.returned)
+      .min()
+      .unwrap_or(usize::MAX);
+
+    let mut child = None;
+    while frame.next < calls.len() && calls[frame.next].invoked < horizon {
+      let i = frame.next;
+      frame.next += 1;
+      if frame.done[i] {
+        continue;
+      }
+      let (value, result) = apply(&frame.value, &calls[i].op);
+      if calls[i].result.as_ref().is_some_and(|r| *r != result) {
+        continue;
+      }
+      let mut done = frame.done.clone();
+      done[i] = true;
+      if seen.insert((done.clone(), value.clone())) {
+        child = Some(Frame {
+          value,
+          done,
+          next: 0,
+        });
+        break;
+      }
+    }
+    match child {
+      Some(child) => stack.push(child),
+      None => {
+        stack.pop();
+      }
+    }
+  }
+  false
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::simulator::{Options, Simulator};
+
+  fn add(v: &str) -> Operation {
+    Operation::Add {
+      key: Bytes::from("k"),
+      value: Bytes::from(v.to_string()),
+    }
+  }
+
+  fn get() -> Operation {
+    Operation::Get {
+      key: Bytes::from("k"),
+    }
+  }
+
+  fn got(v: &str) -> OpResult {
+    OpResult::GetResult(Ok(Bytes::from(v.to_string())))
+  }
+
+  #[test]
+  fn sequential() {
+    let mut h = History::default();
+    h.invoke(1, get());
+    h.respond(1, OpResult::GetResult(Err(OpError::NotFound)));
+    h.invoke(1, add("a"));
+    h.respond(1, OpResult::AddResult(Ok(())));
+    h.invoke(2, get());
+    h.respond(2, got("a"));
+    assert!(h.check().is_ok());
+  }
+
+  #[test]
+  fn concurrent() {
+    // The reads overlap the add, one of them sees it and the other doesn't.
+    let mut h = History::default();
+    h.invoke(1, add("a"));
+    h.invoke(2, get());
+    h.invoke(3, get());
+    h.respond(2, got("a"));
+    h.respond(3, OpResult::GetResult(Err(OpError::NotFound)));
+    h.respond(1, OpResult::AddResult(Ok(())));
+    assert!(h.check().is_ok());
+  }
+
+  #[test]
+  fn stale_read() {
+    // The add completed before the read started, so the read must see it.
+    let mut h = History::default();
+    h.invoke(1, add("a"));
+    h.respond(1, OpResult::AddResult(Ok(())));
+    h.invoke(2, get());
+    h.respond(2, OpResult::GetResult(Err(OpError::NotFound)));
+    let err = h.check().unwrap_err();
+    assert_eq!(err.key, Bytes::from("k"));
+  }
+
+  #[test]
+  fn pending() {
+    // An add without a response may take effect at any point after it was invoked.
+    let mut h = History::default();
+    h.invoke(1, add("a"));
+    h.invoke(2, get());
+    h.respond(2, OpResult::GetResult(Err(OpError::NotFound)));
+    h.invoke(2, get());
+    h.respond(2, got("a"));
+    assert!(h.check().is_ok());
+
+    h.invoke(2, get());
+    h.respond(2, OpResult::GetResult(Err(OpError::NotFound)));
+    assert!(h.check().is_err());
+  }
+
+  #[test]
+  fn simulated_cluster() {
+    let options = Options {
+      steps: 5_000,
+      settle_steps: 5_000,
+      ..Options::default()
+    };
+    for seed in 0..4 {
+      let summary = Simulator::new(seed, options.clone()).run();
+      assert!(summary.is_ok(), "seed {}: {}", seed, summary.unwrap_err());
+    }
+  }
+}
@@ -1,11 +1,13 @@
 use std::collections::VecDeque;
 
+use serde::{Deserialize, Serialize};
+
 use crate::{
   message::ClientRequest,
   types::{OpNumber, ViewNumber},
 };
 
-#[derive(Clone, Debug, PartialEq, Eq, Default)]
+#[derive(Clone, Debug, PartialEq, Eq, Default, Serialize, Deserialize)]
 pub struct Log {
   view: ViewNumber,
   start_op_number: OpNumber,
@@ -35,6 +37,85 @@ impl Log {
     self.end_op_number
   }
 
+  pub fn get(&self, op_number: OpNumber) -> Option<&ClientRequest> {
+    if op_number < self.start_op_number || op_number > self.end_op_number {
+      return None;
+    }
+    self.entries.get(op_number - self.start_op_number)
+  }
+
+  pub fn last_op_number(&self) -> OpNumber {
+    self.end_op_number
+  }
+
+  /// The oldest op still in the log, everything before it is covered by a checkpoint.
+  pub fn first_op_number(&self) -> OpNumber {
+    self.start_op_number.max(1)
+  }
+
+  /// Drops every entry up to and including `op_number`, once a checkpoint covers them.
+  pub fn compact(&mut self, op_number: OpNumber) {
+    if op_number < self.first_op_number() {
+      return;
+    }
+    let drop = (op_number + 1 - self.first_op_number()).min(self.entries.len());
+    self.entries.drain(..drop);
+    self.start_op_number = op_number + 1;
+    self.end_op_number = self.end_op_number.max(op_number);
+  }
+
+  /// Drops every entry after `op_number`.
+  pub fn truncate(&mut self, op_number: OpNumber) {
+    if op_number >= self.end_op_number {
+      return;
+    }
+    let keep = op_number.saturating_sub(self.start_op_number - 1);
+    self.entries.truncate(keep);
+    self.start_op_number = self.start_op_number.min(op_number + 1);
+    self.end_op_number = op_number;
+  }
+
+  /// The entries after `op_number`, as a log starting at `op_number + 1`.
+  pub fn suffix(&self, op_number: OpNumber) -> Log {
+    let start_op_number = (op_number + 1).max(self.first_op_number());
+    let skip = start_op_number - self.first_op_number();
+    Log {
+      view: self.view,
+      start_op_number,
+      end_op_number: self.end_op_number.max(op_number),
+      entries: self.entries.iter().skip(skip).cloned().collect(),
+    }
+  }
+
+  /// Appends the entries of `suffix` that are past the end of this log, `suffix` must not leave
+  /// a gap.
+  pub fn extend(&mut self, suffix: Log) {
+    assert!(suffix.start_op_number <= self.end_op_number + 1);
+    for op_number in self.end_op_number + 1..=suffix.end_op_number {
+      let request = suffix.get(op_number).expect("op within suffix").clone();
+      self.append(suffix.view, request);
+    }
+  }
+
+  /// Combines this log with `other`, which agrees on the ops both of them contain. Returns false,
+  /// leaving this log as is, if there would be a gap between the two.
+  pub fn merge(&mut self, other: Log) -> bool {
+    if other.first_op_number() <= self.first_op_number() {
+      if self.first_op_number() > other.end_op_number + 1 {
+        return false;
+      }
+      let rest = self.suffix(other.end_op_number);
+      *self = other;
+      self.extend(rest);
+    } else {
+      if other.first_op_number() > self.end_op_number + 1 {
+        return false;
+      }
+      self.extend(other);
+    }
+    true
+  }
+
   // pub fn get_entry(&self, op_num: OpNumber) -> Option<&Entry> {
   //     match op_num <= self.checkpoint {
   //         true => None,
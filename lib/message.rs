@@ -4,8 +4,12 @@ use quickcheck::{Arbitrary, Gen};
 use serde::{Deserialize, Serialize};
 
 use crate::{
+  checkpoint::Checkpoint,
+  log::Log,
   operation::{OpResult, Operation},
-  types::{ClientID, CommitID, OpNumber, ReplicaID, RequestID, ViewNumber},
+  types::{
+    ClientID, ClusterID, CommitID, Nonce, OpNumber, ReplicaID, RequestID, Timestamp, ViewNumber,
+  },
 };
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
@@ -32,6 +36,7 @@ impl Arbitrary for ClientRequest {
 pub struct Reply {
   pub view_number: ViewNumber,
   pub request_number: RequestID,
+  pub op_number: OpNumber, // the op that executed the request, or the commit number a read saw
   pub result: OpResult,
 }
 
@@ -41,19 +46,120 @@ pub struct Prepare {
   pub request: ClientRequest,
   pub op_number: OpNumber,
   pub commit_number: CommitID,
+  pub timestamp: Timestamp,
 }
 
+/// `timestamp` echoes the Prepare or Commit being acknowledged, which grants the primary a read
+/// lease starting at that time.
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub struct PrepareOk {
   pub view_number: ViewNumber,
   pub op_number: OpNumber,
   pub replica_number: ReplicaID,
+  pub timestamp: Option<Timestamp>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Commit {
+  pub view_number: ViewNumber,
+  pub commit_number: CommitID,
+  pub timestamp: Timestamp,
+}
+
+/// `commit_number` tells the other replicas whether the sender can continue from their logs, or
+/// needs the checkpoint they start after.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct StartViewChange {
+  pub view_number: ViewNumber,
+  pub commit_number: CommitID,
+  pub replica_number: ReplicaID,
+}
+
+/// `checkpoint` is the one the log continues from, included if the new primary has not executed
+/// the ops the log discarded.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DoViewChange {
+  pub view_number: ViewNumber,
+  pub checkpoint: Option<Checkpoint>,
+  pub log: Log,
+  pub last_normal_view: ViewNumber,
+  pub op_number: OpNumber,
+  pub commit_number: CommitID,
+  pub replica_number: ReplicaID,
+}
+
+/// Like DoViewChange, `checkpoint` is included for a backup that has not executed the ops the log
+/// discarded.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct StartView {
+  pub view_number: ViewNumber,
+  pub checkpoint: Option<Checkpoint>,
+  pub log: Log,
+  pub op_number: OpNumber,
+  pub commit_number: CommitID,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Recovery {
+  pub replica_number: ReplicaID,
+  pub nonce: Nonce,
+}
+
+/// Only the primary includes its log, op number and commit number.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RecoveryResponse {
+  pub view_number: ViewNumber,
+  pub nonce: Nonce,
+  pub log: Option<Log>,
+  pub op_number: OpNumber,
+  pub commit_number: CommitID,
+  pub replica_number: ReplicaID,
+}
+
+/// Asks for the ops after `op_number`.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct GetState {
+  pub view_number: ViewNumber,
+  pub op_number: OpNumber,
+  pub replica_number: ReplicaID,
+}
+
+/// `log` holds the entries after the op number of the corresponding GetState. If the sender
+/// already discarded some of those, `checkpoint` covers them and `log` starts right after it.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct NewState {
+  pub view_number: ViewNumber,
+  pub checkpoint: Option<Checkpoint>,
+  pub log: Log,
+  pub op_number: OpNumber,
+  pub commit_number: CommitID,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub enum ReplicaMessage {
   Prepare(Prepare),
   PrepareOk(PrepareOk),
+  Commit(Commit),
+  StartViewChange(StartViewChange),
+  DoViewChange(DoViewChange),
+  StartView(StartView),
+  Recovery(Recovery),
+  RecoveryResponse(RecoveryResponse),
+  GetState(GetState),
+  NewState(NewState),
+}
+
+/// First message on every connection to a replica, nothing else is accepted before it.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Handshake {
+  pub cluster_id: ClusterID,
+  pub peer: Identity,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Identity {
+  Client(ClientID),
+  Replica(ReplicaID),
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
@@ -61,4 +167,5 @@ pub enum IOMessage {
   Reply(Reply),
   Client(ClientRequest),
   Replica(ReplicaMessage),
+  Handshake(Handshake),
 }
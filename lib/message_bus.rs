@@ -1,54 +1,215 @@
-use core::time;
 use std::{
-  io::{self, Error, ErrorKind},
-  net::{SocketAddr, TcpListener},
-  os::fd::{IntoRawFd, RawFd},
+  fs::File,
+  io::{self, ErrorKind},
+  net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+  os::fd::{AsRawFd, FromRawFd, IntoRawFd, RawFd},
   ptr,
-  thread::sleep,
+  thread::{self, sleep, JoinHandle},
+  time::{Duration, Instant},
 };
 
+use std::collections::VecDeque;
+
+use hashbrown::HashMap;
 use io_uring::{
   cqueue::{self, Entry},
   opcode,
-  squeue::PushError,
+  squeue::{self, PushError},
   types, IoUring,
 };
-use log::debug;
+use log::{debug, warn};
 use slab::Slab;
 
 use crate::{
-  message::IOMessage,
-  replica::Replica,
-  types::{ClientID, ReplicaID},
+  checkpoint::Checkpoint,
+  configuration::Configuration,
+  journal::{self, Journal},
+  message::{Handshake, IOMessage, Identity},
+  network::{self, ConnectionTable, FrameBuffer},
+  replica::{Destination, JournalWrite, Replica},
+  snapshot,
+  types::{ClientID, ConnectionID, ReplicaID, ViewNumber},
 };
 
+// The low byte of user_data is the operation, the rest is the connection it belongs to.
+const ACCEPT: u64 = 0;
+const READ: u64 = 1;
+const JOURNAL_WRITE: u64 = 2;
+const JOURNAL_SYNC: u64 = 3;
+const WRITE: u64 = 4;
+const SOCKET: u64 = 5; // belongs to a replica rather than a connection
+const CONNECT: u64 = 6;
+
+fn user_data(op: u64, conn_id: ConnectionID) -> u64 {
+  ((conn_id as u64) << 8) | op
+}
+
+/// Interval at which the replica is ticked, its timeouts are counted in ticks.
+pub const TICK: Duration = Duration::from_millis(1);
+
+const READ_SIZE: usize = 16 * 1024;
+/// Bytes queued for a connection beyond which further messages to it are dropped, a peer that
+/// does not keep up must not grow our memory without bound. An empty queue takes a message of
+/// any size, a checkpoint or log can be larger than this.
+const SEND_QUEUE_MAX: usize = 64 * 1024 * 1024;
+/// Bounds of the delay before reconnecting to a replica, which doubles with every failed attempt.
+const RECONNECT_DELAY_MIN: Duration = Duration::from_millis(50);
+const RECONNECT_DELAY_MAX: Duration = Duration::from_secs(5);
+
+/// Reads and writes are in flight independently, a read is always armed while the connection is
+/// open so a peer closing it is noticed.
 pub struct Connection {
   peer: Option<PeerType>,
-  fd: RawFd,
+  stream: TcpStream,
   state: CState,
-  buffer: Vec<u8>,
+  buffer: Vec<u8>, // target of the read in flight
+  frames: FrameBuffer,
+  reading: bool,
+  send_queue: VecDeque<Vec<u8>>, // the front is being written
+  written: usize,                // of the front of the send queue
+  writing: bool,
+  queued_bytes: usize,
+}
+
+impl Connection {
+  fn new(stream: TcpStream, peer: Option<PeerType>) -> Self {
+    // Anyone can connect, only a peer that identified itself may send large frames.
+    let frames = match peer {
+      None => FrameBuffer::with_size_max(network::HANDSHAKE_SIZE_MAX),
+      Some(_) => FrameBuffer::default(),
+    };
+    Connection {
+      peer,
+      stream,
+      state: CState::Open,
+      buffer: vec![0; READ_SIZE],
+      frames,
+      reading: false,
+      send_queue: VecDeque::new(),
+      written: 0,
+      writing: false,
+      queued_bytes: 0,
+    }
+  }
 }
 
+#[derive(Debug)]
 pub enum PeerType {
   Unknown,
   Client(ClientID),
   Replica(ReplicaID),
 }
 
+// State machine for connection state
+#[derive(Debug, PartialEq, Eq)]
+enum CState {
+  Connecting, // messages are queued until connected, nothing else is in flight meanwhile
+  Open,
+  Closing, // shut down, waiting for the operations in flight to complete
+}
+
+/// One linked write+fsync of the journal is in flight at a time, records queued meanwhile go out
+/// together in the next one. Messages are held back until the records queued before them are
+/// durable.
+#[derive(Default)]
+struct JournalWrites {
+  in_flight: Option<Vec<u8>>, // owned until the write completes
+  in_flight_held: Vec<(Destination, IOMessage)>,
+  retired: Option<File>, // journal replaced by a checkpoint, the write in flight still uses it
+  pending: Vec<u8>,
+  pending_held: Vec<(Destination, IOMessage)>,
+  saving: Option<SnapshotWrite>,
+  next_snapshot: Option<Snapshot>, // queued while the one before is saved
+}
+
+impl JournalWrites {
+  /// Records queued after an installed checkpoint may skip over its ops, they are neither
+  /// written nor are the messages behind them sent until its snapshot is durable.
+  fn blocked(&self) -> bool {
+    self.saving.as_ref().is_some_and(|s| s.installed)
+      || self.next_snapshot.as_ref().is_some_and(|s| s.installed)
+  }
+
+  /// The journal replacing the current one holds every record after the latest checkpoint.
+  fn latest_journal(&mut self) -> Option<&mut Vec<u8>> {
+    match (&mut self.next_snapshot, &mut self.saving) {
+      (Some(next), _) => Some(&mut next.journal),
+      (None, Some(saving)) => Some(&mut saving.journal),
+      (None, None) => None,
+    }
+  }
+}
+
+/// A checkpoint along with the journal that replaces the current one once its snapshot is
+/// durable, the records it was queued with and every one queued after it.
+struct Snapshot {
+  view: ViewNumber,
+  checkpoint: Checkpoint,
+  journal: Vec<u8>,
+  installed: bool,
+}
+
+/// The snapshot is saved off the loop thread, it can take far longer than a tick.
+struct SnapshotWrite {
+  thread: JoinHandle<io::Result<()>>,
+  journal: Vec<u8>,
+  installed: bool,
+}
+
+/// Outbound connection to another replica, messages to it are only ever sent on this one. It is
+/// opened on startup and reopened whenever it is lost.
+struct Peer {
+  addr: Box<RawSockAddr>, // read by connect(2) while in flight
+  conn_id: Option<ConnectionID>,
+  creating_socket: bool,
+  connected_at: Option<Instant>,
+  retry_at: Instant,
+  backoff: Duration,
+}
+
+impl Peer {
+  fn new(addr: SocketAddr) -> Self {
+    Peer {
+      addr: Box::new(RawSockAddr::new(addr)),
+      conn_id: None,
+      creating_socket: false,
+      connected_at: None,
+      retry_at: Instant::now(),
+      backoff: RECONNECT_DELAY_MIN,
+    }
+  }
+
+  /// The backoff only starts over once a connection stayed up for a while, a replica that accepts
+  /// connections and closes them right away is retried no more often than one that is down.
+  fn disconnected(&mut self) {
+    let now = Instant::now();
+    if self
+      .connected_at
+      .take()
+      .is_some_and(|at| now - at >= RECONNECT_DELAY_MAX)
+    {
+      self.backoff = RECONNECT_DELAY_MIN;
+    }
+    self.conn_id = None;
+    self.retry_at = now + self.backoff;
+    self.backoff = (self.backoff * 2).min(RECONNECT_DELAY_MAX);
+  }
+}
+
 pub struct MessageBus {
   ring: IoUring,
+  conf: Configuration,
+  replica_id: ReplicaID,
+  replica: Replica,
+  journal: Journal,
+  journal_writes: JournalWrites,
 
   connections: Slab<Connection>,
+  peers: HashMap<ReplicaID, Peer>,
+  clients: ConnectionTable, // the connection each client identified itself on last
 
   listener_fd: RawFd,
-  // backlog: VecDeque<u8>,
-}
-
-// State machine for connection state
-#[derive(Debug)]
-enum CState {
-  Reading,
-  Writing,
+  next_tick: Instant,
 }
 
 #[derive(Debug)]
@@ -70,37 +231,80 @@ impl From<io::Error> for IOError {
 }
 
 impl MessageBus {
-  pub fn new(addr: SocketAddr, replica: Replica) -> Self {
+  pub fn new(
+    conf: Configuration,
+    replica_id: ReplicaID,
+    replica: Replica,
+    journal: Journal,
+  ) -> Self {
     let ring = IoUring::new(1024).unwrap();
-    let listener = TcpListener::bind(addr).unwrap();
+    let listener = TcpListener::bind(conf.find_addr(replica_id)).unwrap();
     listener.set_nonblocking(true).unwrap();
 
     debug!("Listening on {:?}", listener.local_addr().unwrap());
+    let peers = (0..conf.replicas.len())
+      .filter(|&id| id != replica_id)
+      .map(|id| (id, Peer::new(conf.find_addr(id))))
+      .collect();
     MessageBus {
       ring,
+      conf,
+      replica_id,
+      replica,
+      journal,
+      journal_writes: JournalWrites::default(),
       connections: Slab::with_capacity(64),
+      peers,
+      clients: ConnectionTable::new(),
       listener_fd: listener.into_raw_fd(),
-      // backlog: VecDeque::new(),
+      next_tick: Instant::now() + TICK,
     }
   }
 
-  pub fn run(&mut self) -> io::Result<()> {
-    self.register_accept().unwrap();
+  /// Only returns on errors that leave the replica unable to continue, anything going wrong on a
+  /// single connection just closes that connection.
+  pub fn run(&mut self) -> Result<(), IOError> {
+    self.register_accept()?;
 
     loop {
-      self.ring.submit().unwrap();
+      self.ring.submit()?;
+      // Time moves on before anything that arrived meanwhile is handled, a primary that was
+      // stalled must notice that its lease expired before serving reads.
+      self.tick();
       let cqes: Vec<Entry> = self.ring.completion().collect();
       for cqe in cqes {
-        // if let Err(err) = self.handle_event(cqe) {
-        //   panic!("{:?}", err);
-        // }
+        self.handle_event(cqe)?;
       }
 
-      // while let Some((replica_id, msg)) = self.replica.dequeue_replica_msg() {
-      //   self
-      // }
+      self.poll_snapshot()?;
+      self.flush()?;
+      self.connect_peers()?;
 
-      sleep(time::Duration::from_millis(1));
+      sleep(TICK);
+    }
+  }
+
+  /// Ticks the replica once for every interval that passed, however long the loop took.
+  fn tick(&mut self) {
+    let now = Instant::now();
+    if now < self.next_tick {
+      return;
+    }
+    // After a long stall every lease has expired and every timeout has fired once the longest
+    // of them passed, the ticks beyond that are skipped.
+    let timeouts = &self.conf.timeouts;
+    let behind_max = timeouts
+      .view_change
+      .max(timeouts.recovery)
+      .max(timeouts.prepare_retransmit) as u32
+      + 1;
+    if now - self.next_tick >= TICK * behind_max {
+      warn!("Event loop stalled for {:?}", now - self.next_tick);
+      self.next_tick = now - TICK * (behind_max - 1);
+    }
+    while self.next_tick <= now {
+      self.replica.tick();
+      self.next_tick += TICK;
     }
   }
 
@@ -111,122 +315,602 @@ impl MessageBus {
       ptr::null_mut(),
     )
     .build()
-    .user_data(0);
+    .user_data(ACCEPT);
     unsafe { self.ring.submission().push(&entry)? };
     Ok(())
   }
 
-  fn register_read(&mut self, conn_id: usize) -> Result<(), IOError> {
+  fn register_read(&mut self, conn_id: ConnectionID) -> Result<(), IOError> {
     let conn = &mut self.connections[conn_id];
     let entry = opcode::Read::new(
-      types::Fd(conn.fd),
+      types::Fd(conn.stream.as_raw_fd()),
       conn.buffer.as_mut_ptr(),
       conn.buffer.len() as u32,
     )
     .build()
-    .user_data((conn_id + 1) as u64);
+    .user_data(user_data(READ, conn_id));
 
     unsafe { self.ring.submission().push(&entry) }?;
     self.ring.submit()?;
+    conn.reading = true;
 
     Ok(())
   }
 
-  fn read_exact(s: &mut Vec<u8>, buf: &mut [u8]) -> Result<(), Error> {
-    let mut pos = 0;
+  fn register_write(&mut self, conn_id: ConnectionID) -> Result<(), IOError> {
+    let conn = &mut self.connections[conn_id];
+    let buf = &conn.send_queue.front().expect("something to write")[conn.written..];
+    let entry = opcode::Write::new(
+      types::Fd(conn.stream.as_raw_fd()),
+      buf.as_ptr(),
+      buf.len() as u32,
+    )
+    .build()
+    .user_data(user_data(WRITE, conn_id));
+
+    unsafe { self.ring.submission().push(&entry) }?;
+    self.ring.submit()?;
+    conn.writing = true;
+
+    Ok(())
+  }
+
+  fn handle_event(&mut self, cqe: cqueue::Entry) -> Result<(), IOError> {
+    debug!("Event {:?}", cqe);
+    let conn_id = (cqe.user_data() >> 8) as ConnectionID;
+    match cqe.user_data() & 0xff {
+      ACCEPT => self.handle_accept(cqe),
+      READ => self.handle_read(conn_id, cqe),
+      WRITE => self.handle_write(conn_id, cqe),
+      SOCKET => self.handle_socket(conn_id, cqe),
+      CONNECT => self.handle_connect(conn_id, cqe),
+      JOURNAL_WRITE | JOURNAL_SYNC => self.handle_journal_event(cqe),
+      op => unreachable!("unknown operation {}", op),
+    }
+  }
+
+  fn handle_accept(&mut self, cqe: cqueue::Entry) -> Result<(), IOError> {
+    self.register_accept()?; // Make sure we still accept new connections
+
+    let result = cqe.result();
+    if result < 0 {
+      warn!("Accept failed: {}", io::Error::from_raw_os_error(-result));
+      return Ok(());
+    }
+
+    let stream = unsafe { TcpStream::from_raw_fd(result as RawFd) };
+    if let Err(e) = stream.set_nodelay(true) {
+      warn!("Dropping accepted connection: {}", e);
+      return Ok(());
+    }
+    let conn_id = self.connections.insert(Connection::new(stream, None));
+    debug!("Accepted connection {}", conn_id);
+
+    self.register_read(conn_id) // Register a read on current connection
+  }
+
+  fn handle_read(&mut self, conn_id: ConnectionID, cqe: cqueue::Entry) -> Result<(), IOError> {
+    let result = cqe.result();
+    let conn = &mut self.connections[conn_id];
+    conn.reading = false;
+    if conn.state == CState::Closing {
+      self.close(conn_id);
+      return Ok(());
+    }
+    if result <= 0 {
+      match result {
+        0 => debug!("Connection {} ({:?}) closed", conn_id, conn.peer),
+        _ => warn!(
+          "Read on connection {} failed: {}",
+          conn_id,
+          io::Error::from_raw_os_error(-result)
+        ),
+      }
+      self.close(conn_id);
+      return Ok(());
+    }
+
+    conn.frames.extend(&conn.buffer[..result as usize]);
+    // One message at a time, the handshake lifts the frame limit for the ones behind it.
+    loop {
+      let msg = match self.connections[conn_id].frames.next_message() {
+        Ok(Some(msg)) => msg,
+        Ok(None) => break,
+        Err(e) => {
+          warn!("Bad message on connection {}: {}", conn_id, e);
+          self.close(conn_id);
+          return Ok(());
+        }
+      };
+      debug!("msg: {:?}", msg);
+      if let Err(reason) = self.dispatch(conn_id, msg) {
+        warn!("Closing connection {}: {}", conn_id, reason);
+        self.close(conn_id);
+        return Ok(());
+      }
+    }
+    self.register_read(conn_id) // Continue reading after this
+  }
+
+  /// Nothing but the handshake is accepted before it, and after it only what the peer it
+  /// identified as may send.
+  fn dispatch(&mut self, conn_id: ConnectionID, msg: IOMessage) -> Result<(), String> {
+    match (msg, &self.connections[conn_id].peer) {
+      (IOMessage::Handshake(handshake), None) => self.on_handshake(conn_id, handshake),
+      (_, None) => Err("message before the handshake".to_string()),
+      (IOMessage::Client(req), Some(PeerType::Client(client_id)))
+        if req.client_id == *client_id =>
+      {
+        self.replica.on_client_request(req);
+        Ok(())
+      }
+      (IOMessage::Replica(msg), Some(PeerType::Replica(_))) => {
+        self.replica.on_replica_message(msg);
+        Ok(())
+      }
+      (_, Some(peer)) => Err(format!("unexpected message from {:?}", peer)),
+    }
+  }
+
+  fn on_handshake(&mut self, conn_id: ConnectionID, handshake: Handshake) -> Result<(), String> {
+    if handshake.cluster_id != self.conf.cluster_id {
+      return Err(format!(
+        "peer belongs to cluster {}, not {}",
+        handshake.cluster_id, self.conf.cluster_id
+      ));
+    }
+    let (peer, size_max) = match handshake.peer {
+      Identity::Client(client_id) => {
+        // A client that reconnects gets its replies on the new connection.
+        self.clients.insert(client_id, conn_id);
+        (PeerType::Client(client_id), network::CLIENT_FRAME_SIZE_MAX)
+      }
+      // Only replicas send checkpoints and logs.
+      Identity::Replica(replica_id)
+        if replica_id < self.conf.replicas.len() && replica_id != self.replica_id =>
+      {
+        (PeerType::Replica(replica_id), network::FRAME_SIZE_MAX)
+      }
+      Identity::Replica(replica_id) => return Err(format!("unknown replica {}", replica_id)),
+    };
+    debug!("Connection {} is from {:?}", conn_id, peer);
+    let conn = &mut self.connections[conn_id];
+    conn.peer = Some(peer);
+    conn.frames.set_size_max(size_max);
+    Ok(())
+  }
+
+  /// Moves the replica's output into the journal write and the send path, in that order.
+  fn flush(&mut self) -> Result<(), IOError> {
+    while let Some(write) = self.replica.dequeue_journal_write() {
+      match write {
+        JournalWrite::Record(record) => {
+          let buf = journal::encode(&record);
+          let writes = &mut self.journal_writes;
+          writes.pending.extend_from_slice(&buf);
+          if let Some(journal) = writes.latest_journal() {
+            journal.extend_from_slice(&buf);
+          }
+        }
+        JournalWrite::Checkpoint {
+          view,
+          checkpoint,
+          records,
+          installed,
+        } => self.checkpoint(Snapshot {
+          view,
+          checkpoint,
+          journal: records.iter().flat_map(journal::encode).collect(),
+          installed,
+        }),
+      }
+    }
+
+    let mut outgoing = Vec::new();
+    while let Some(out) = self.replica.dequeue_message() {
+      outgoing.push(out);
+    }
 
-    while pos < buf.len() {
-      if s.is_empty() {
-        panic!("TODO");
-        // return Err(Error::new(ErrorKind::UnexpectedEof, "buffer too small"));
+    let writes = &mut self.journal_writes;
+    if !writes.pending.is_empty() || writes.blocked() {
+      writes.pending_held.extend(outgoing);
+    } else if writes.in_flight.is_some() {
+      writes.in_flight_held.extend(outgoing);
+    } else {
+      for (dest, msg) in outgoing {
+        self.send(dest, msg)?;
       }
+    }
+    self.write_pending()
+  }
+
+  /// Starts saving the snapshot, unless one is being saved already. Only the latest checkpoint
+  /// is saved after it, that one covers the ops of any queued before.
+  fn checkpoint(&mut self, snapshot: Snapshot) {
+    let writes = &mut self.journal_writes;
+    if writes.saving.is_none() {
+      self.save_snapshot(snapshot);
+      return;
+    }
+    let installed = snapshot.installed || writes.next_snapshot.take().is_some_and(|s| s.installed);
+    writes.next_snapshot = Some(Snapshot {
+      installed,
+      ..snapshot
+    });
+  }
+
+  fn save_snapshot(&mut self, snapshot: Snapshot) {
+    debug!("Saving checkpoint at op {}", snapshot.checkpoint.op_number);
+    let path = self.journal.snapshot_path();
+    let Snapshot {
+      view,
+      checkpoint,
+      journal,
+      installed,
+    } = snapshot;
+    let thread = thread::spawn(move || snapshot::save(&path, view, &checkpoint));
+    self.journal_writes.saving = Some(SnapshotWrite {
+      thread,
+      journal,
+      installed,
+    });
+  }
 
-      let chunk_size = std::cmp::min(s.len(), buf.len() - pos);
-      buf[pos..pos + chunk_size].copy_from_slice(&s[..chunk_size]);
-      s.drain(..chunk_size);
-      pos += chunk_size;
+  /// Once the snapshot is durable, the journal is rewritten to only the records after it. That
+  /// covers the records still pending, so they are dropped and the messages held for them are
+  /// durable now. With another checkpoint queued, the journal is only rewritten after that one.
+  fn poll_snapshot(&mut self) -> Result<(), IOError> {
+    let writes = &mut self.journal_writes;
+    if !writes
+      .saving
+      .as_ref()
+      .is_some_and(|s| s.thread.is_finished())
+    {
+      return Ok(());
+    }
+    let saving = writes.saving.take().expect("checked above");
+    saving
+      .thread
+      .join()
+      .unwrap_or_else(|_| Err(io::Error::other("saving the snapshot panicked")))?;
+
+    if let Some(next) = self.journal_writes.next_snapshot.take() {
+      self.save_snapshot(next);
+      return self.write_pending();
+    }
+    let old = self.journal.rewrite(&saving.journal)?;
+    let writes = &mut self.journal_writes;
+    writes.pending.clear();
+    if writes.in_flight.is_some() {
+      // Only the first replaced file can still be written to.
+      writes.retired.get_or_insert(old);
     }
+    self.write_pending()
+  }
+
+  /// Writes the pending records unless a write is in flight or a snapshot blocks them. Messages
+  /// that were only held back for a snapshot follow the records written before them.
+  fn write_pending(&mut self) -> Result<(), IOError> {
+    let writes = &mut self.journal_writes;
+    if writes.blocked() {
+      return Ok(());
+    }
+    if !writes.pending.is_empty() {
+      if writes.in_flight.is_none() {
+        self.register_journal_write()?;
+      }
+      return Ok(());
+    }
+    let held = std::mem::take(&mut writes.pending_held);
+    if writes.in_flight.is_some() {
+      writes.in_flight_held.extend(held);
+    } else {
+      for (dest, msg) in held {
+        self.send(dest, msg)?;
+      }
+    }
+    Ok(())
+  }
+
+  fn register_journal_write(&mut self) -> Result<(), IOError> {
+    let buf = std::mem::take(&mut self.journal_writes.pending);
+    let (fd, offset) = self.journal.reserve(buf.len());
+
+    let write = opcode::Write::new(types::Fd(fd), buf.as_ptr(), buf.len() as u32)
+      .offset(offset)
+      .build()
+      .flags(squeue::Flags::IO_LINK)
+      .user_data(JOURNAL_WRITE);
+    let fsync = opcode::Fsync::new(types::Fd(fd))
+      .flags(types::FsyncFlags::DATASYNC)
+      .build()
+      .user_data(JOURNAL_SYNC);
+    unsafe { self.ring.submission().push_multiple(&[write, fsync])? };
+    self.ring.submit()?;
 
+    let writes = &mut self.journal_writes;
+    writes.in_flight = Some(buf);
+    writes.in_flight_held = std::mem::take(&mut writes.pending_held);
     Ok(())
   }
 
-  pub fn read_message(s: &mut Vec<u8>) -> Result<IOMessage, Error> {
-    let mut header = [0u8; 4];
-    Self::read_exact(s, &mut header)?;
+  /// The replica cannot keep its promises without the journal, so any failure is fatal. A short
+  /// write cancels the linked fsync and is reported by it.
+  fn handle_journal_event(&mut self, cqe: cqueue::Entry) -> Result<(), IOError> {
+    let result = cqe.result();
+    if result < 0 {
+      return Err(IOError::IoError(io::Error::from_raw_os_error(-result)));
+    }
+    if cqe.user_data() == JOURNAL_WRITE {
+      let len = self.journal_writes.in_flight.as_ref().map_or(0, Vec::len);
+      if result as usize != len {
+        return Err(IOError::IoError(io::Error::new(
+          ErrorKind::WriteZero,
+          format!("short journal write, {} of {} bytes", result, len),
+        )));
+      }
+      return Ok(());
+    }
 
-    let msg_size: usize = u32::from_be_bytes(header)
-      .try_into()
-      .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    self.journal_writes.in_flight = None;
+    self.journal_writes.retired = None;
+    let held = std::mem::take(&mut self.journal_writes.in_flight_held);
+    for (dest, msg) in held {
+      self.send(dest, msg)?;
+    }
+    self.write_pending()
+  }
 
-    let mut buf = vec![0u8; msg_size];
-    Self::read_exact(s, &mut buf)?;
+  /// Continues with the rest of a short write, or with the next frames in the queue.
+  fn handle_write(&mut self, conn_id: ConnectionID, cqe: cqueue::Entry) -> Result<(), IOError> {
+    let result = cqe.result();
+    let conn = &mut self.connections[conn_id];
+    conn.writing = false;
+    if conn.state == CState::Closing || result <= 0 {
+      if result < 0 {
+        warn!(
+          "Write on connection {} failed: {}",
+          conn_id,
+          io::Error::from_raw_os_error(-result)
+        );
+      }
+      self.close(conn_id);
+      return Ok(());
+    }
 
-    bincode::deserialize(&buf).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    conn.written += result as usize;
+    let front_len = conn.send_queue.front().map_or(0, Vec::len);
+    if conn.written == front_len {
+      conn.send_queue.pop_front();
+      conn.queued_bytes -= front_len;
+      conn.written = 0;
+    }
+    if !conn.send_queue.is_empty() {
+      self.register_write(conn_id)?;
+    }
+    Ok(())
   }
 
-  pub fn handle_accept(&mut self, cqe: cqueue::Entry) -> Result<(), IOError> {
-    let socket = cqe.result() as RawFd;
-    let conn = Connection {
-      fd: socket,
-      state: CState::Reading,
-      buffer: vec![0; 1024],
-      peer: None,
+  /// Messages to a replica or client that is not connected are dropped. The protocol
+  /// retransmits what it needs on its own, and a client retrying gets the reply from the client
+  /// table.
+  fn send(&mut self, dest: Destination, msg: IOMessage) -> Result<(), IOError> {
+    let conn_id = match dest {
+      Destination::Replica(replica_id) => self.peers.get(&replica_id).and_then(|peer| peer.conn_id),
+      Destination::Client(client_id) => self.clients.get(&client_id).copied(),
     };
+    match conn_id {
+      Some(conn_id) => self.enqueue(conn_id, &msg),
+      None => {
+        debug!("{:?} is not connected, dropping {:?}", dest, msg);
+        Ok(())
+      }
+    }
+  }
+
+  /// Starts connecting to every replica we are not connected to and are not backing off from.
+  fn connect_peers(&mut self) -> Result<(), IOError> {
+    let now = Instant::now();
+    for (&replica_id, peer) in self.peers.iter_mut() {
+      if peer.conn_id.is_some() || peer.creating_socket || now < peer.retry_at {
+        continue;
+      }
+      let entry = opcode::Socket::new(peer.addr.domain(), SOCK_STREAM | SOCK_CLOEXEC, 0)
+        .build()
+        .user_data(user_data(SOCKET, replica_id));
+      unsafe { self.ring.submission().push(&entry)? };
+      peer.creating_socket = true;
+    }
+    Ok(())
+  }
+
+  fn handle_socket(&mut self, replica_id: ReplicaID, cqe: cqueue::Entry) -> Result<(), IOError> {
+    let peer = self.peers.get_mut(&replica_id).expect("known replica");
+    peer.creating_socket = false;
+    let result = cqe.result();
+    if result < 0 {
+      warn!(
+        "Creating a socket failed: {}",
+        io::Error::from_raw_os_error(-result)
+      );
+      peer.disconnected();
+      return Ok(());
+    }
+
+    let stream = unsafe { TcpStream::from_raw_fd(result as RawFd) };
+    let (addr, addr_len) = peer.addr.as_ptr();
+    let entry = opcode::Connect::new(types::Fd(stream.as_raw_fd()), addr.cast(), addr_len).build();
+    let mut conn = Connection::new(stream, Some(PeerType::Replica(replica_id)));
+    conn.state = CState::Connecting;
     let conn_id = self.connections.insert(conn);
+    peer.conn_id = Some(conn_id);
 
-    self.register_read(conn_id)?; // Register a read on current connection
-    self.register_accept()?; // Make sure we still accept new connections
+    let entry = entry.user_data(user_data(CONNECT, conn_id));
+    unsafe { self.ring.submission().push(&entry)? };
+    self.ring.submit()?;
     Ok(())
   }
 
-  // fn handle_event(&mut self, cqe: cqueue::Entry) -> Result<(), IOError> {
-  //   debug!("Event {:?}", cqe);
-  //   let result = cqe.result();
-
-  //   if result < 0 {
-  //     let err = io::Error::from_raw_os_error(-result);
-  //     debug!("CQE error: {:?}", err);
-  //     return Err(IOError::IoError(err));
-  //   }
-  //   let conn_id = cqe.user_data();
-  //   debug!("Conn id: {}", conn_id);
-  //   match conn_id {
-  //     0 => self.handle_accept(cqe)?,
-  //     _ => self.handle_connection_event((conn_id - 1) as usize, cqe)?,
-  //   }
-
-  //   Ok(())
-  // }
-
-  // fn handle_connection_event(&mut self, conn_id: usize, cqe: cqueue::Entry) -> Result<(), IOError> {
-  //   let conn = &mut self.connections[conn_id];
-  //   let result = cqe.result();
-  //   match conn.state {
-  //     CState::Reading => {
-  //       let msg = Self::read_message(&mut conn.buffer).unwrap();
-  //       self.register_read(conn_id)?; // Continue reading after this
-  //       debug!("msg: {:?}", msg);
-  //       // debug!("buffer: {}", String::from_utf8_lossy(&conn.buffer.clone()));
-
-  //       match msg {
-  //         IOMessage::Client(req) => {
-  //           self.replica.on_client_request(req, conn_id);
-  //           // replica
-  //           //   .lock()
-  //           //   .unwrap()
-  //           //   .on_client_request(req, s.0.try_clone().unwrap());
-
-  //           // if let Some(resp) = response {
-  //           //   conn.write_message(&resp).await?;
-  //           // }
-  //         }
-
-  //         IOMessage::Replica(msg) => todo!(),
-  //         IOMessage::Reply(_) => todo!(),
-  //       }
-  //     }
-  //     CState::Writing => todo!(),
-  //   }
-  //   Ok(())
-  // }
+  /// Introduces us to the replica before anything that was queued while connecting.
+  fn handle_connect(&mut self, conn_id: ConnectionID, cqe: cqueue::Entry) -> Result<(), IOError> {
+    let result = cqe.result();
+    let conn = &mut self.connections[conn_id];
+    if result < 0 {
+      debug!(
+        "Connecting to {:?} failed: {}",
+        conn.peer,
+        io::Error::from_raw_os_error(-result)
+      );
+      self.close(conn_id);
+      return Ok(());
+    }
+
+    debug!("Connected to {:?}", conn.peer);
+    if let Err(e) = conn.stream.set_nodelay(true) {
+      warn!("Connection to {:?} failed: {}", conn.peer, e);
+      self.close(conn_id);
+      return Ok(());
+    }
+    conn.state = CState::Open;
+    let handshake = network::encode_message(&IOMessage::Handshake(Handshake {
+      cluster_id: self.conf.cluster_id,
+      peer: Identity::Replica(self.replica_id),
+    }))?;
+    conn.queued_bytes += handshake.len();
+    conn.send_queue.push_front(handshake);
+    if let Some(PeerType::Replica(replica_id)) = conn.peer {
+      self
+        .peers
+        .get_mut(&replica_id)
+        .expect("known replica")
+        .connected_at = Some(Instant::now());
+    }
+
+    self.register_read(conn_id)?;
+    self.register_write(conn_id)
+  }
+
+  /// Queues the message on the connection and starts writing unless a write is in flight already.
+  fn enqueue(&mut self, conn_id: ConnectionID, msg: &IOMessage) -> Result<(), IOError> {
+    let frame = match network::encode_message(msg) {
+      Ok(frame) => frame,
+      Err(e) => {
+        warn!("Dropping message to connection {}: {}", conn_id, e);
+        return Ok(());
+      }
+    };
+    let conn = &mut self.connections[conn_id];
+    if conn.state == CState::Closing {
+      return Ok(());
+    }
+    if !conn.send_queue.is_empty() && conn.queued_bytes + frame.len() > SEND_QUEUE_MAX {
+      warn!(
+        "Send queue of connection {} ({:?}) is full, dropping {:?}",
+        conn_id, conn.peer, msg
+      );
+      return Ok(());
+    }
+
+    conn.queued_bytes += frame.len();
+    // Frames behind the one being written go out together in the next write.
+    let back_in_flight = conn.writing && conn.send_queue.len() == 1;
+    match conn.send_queue.back_mut() {
+      Some(back) if !back_in_flight => back.extend(frame),
+      _ => conn.send_queue.push_back(frame),
+    }
+    if !conn.writing && conn.state == CState::Open {
+      self.register_write(conn_id)?;
+    }
+    Ok(())
+  }
+
+  /// Shuts the socket down, which completes the operations in flight, and only then removes the
+  /// connection since the kernel might still be using its buffers.
+  fn close(&mut self, conn_id: ConnectionID) {
+    let conn = &mut self.connections[conn_id];
+    if conn.state != CState::Closing {
+      let _ = conn.stream.shutdown(Shutdown::Both);
+      conn.state = CState::Closing;
+    }
+    if conn.reading || conn.writing {
+      return;
+    }
+
+    let conn = self.connections.remove(conn_id);
+    match conn.peer {
+      Some(PeerType::Replica(replica_id)) => {
+        if let Some(peer) = self.peers.get_mut(&replica_id) {
+          if peer.conn_id == Some(conn_id) {
+            peer.disconnected();
+          }
+        }
+      }
+      Some(PeerType::Client(client_id)) if self.clients.get(&client_id) == Some(&conn_id) => {
+        self.clients.remove(&client_id);
+      }
+      _ => {}
+    }
+  }
+}
+
+const AF_INET: i32 = 2;
+const AF_INET6: i32 = 10;
+const SOCK_STREAM: i32 = 1;
+const SOCK_CLOEXEC: i32 = 0o2000000;
+
+/// sockaddr_in and sockaddr_in6, laid out the way connect(2) expects them.
+#[repr(C)]
+struct SockAddrIn {
+  family: u16,
+  port: [u8; 2],
+  addr: [u8; 4],
+  zero: [u8; 8],
+}
+
+#[repr(C)]
+struct SockAddrIn6 {
+  family: u16,
+  port: [u8; 2],
+  flowinfo: u32,
+  addr: [u8; 16],
+  scope_id: u32,
+}
+
+enum RawSockAddr {
+  V4(SockAddrIn),
+  V6(SockAddrIn6),
+}
+
+impl RawSockAddr {
+  fn new(addr: SocketAddr) -> Self {
+    match addr {
+      SocketAddr::V4(addr) => RawSockAddr::V4(SockAddrIn {
+        family: AF_INET as u16,
+        port: addr.port().to_be_bytes(),
+        addr: addr.ip().octets(),
+        zero: [0; 8],
+      }),
+      SocketAddr::V6(addr) => RawSockAddr::V6(SockAddrIn6 {
+        family: AF_INET6 as u16,
+        port: addr.port().to_be_bytes(),
+        flowinfo: addr.flowinfo().to_be(),
+        addr: addr.ip().octets(),
+        scope_id: addr.scope_id(),
+      }),
+    }
+  }
+
+  fn domain(&self) -> i32 {
+    match self {
+      RawSockAddr::V4(_) => AF_INET,
+      RawSockAddr::V6(_) => AF_INET6,
+    }
+  }
+
+  fn as_ptr(&self) -> (*const u8, u32) {
+    match self {
+      RawSockAddr::V4(addr) => (ptr::from_ref(addr).cast(), size_of::<SockAddrIn>() as u32),
+      RawSockAddr::V6(addr) => (ptr::from_ref(addr).cast(), size_of::<SockAddrIn6>() as u32),
+    }
+  }
 }
@@ -4,16 +4,21 @@
 // #[macro_use(quickcheck)]
 // extern crate quickcheck_macros;
 
+pub mod checkpoint;
 pub mod client;
 pub mod client_table;
 pub mod configuration;
+pub mod error;
+pub mod journal;
 pub mod kvstore;
+pub mod linearizability;
 pub mod log;
 pub mod message;
 pub mod message_bus;
 pub mod network;
 pub mod operation;
 pub mod replica;
-pub mod take_two;
+pub mod simulator;
+pub mod snapshot;
 pub mod types;
 pub mod utils;
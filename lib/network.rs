@@ -1,36 +1,26 @@
-use core::panic;
 use std::{
   io::{Error, ErrorKind, Read, Write},
-  net::{SocketAddr, TcpListener, TcpStream},
+  net::TcpStream,
 };
 
 use hashbrown::HashMap;
 
 use crate::{
   message::IOMessage,
-  replica::Replica,
+  operation::{KEY_SIZE_MAX, VALUE_SIZE_MAX},
   types::{ClientID, ConnectionID},
 };
 
 pub type ConnectionTable = HashMap<ClientID, ConnectionID>;
 
-fn read_exact(s: &mut Vec<u8>, buf: &mut [u8]) -> Result<(), Error> {
-  let mut pos = 0;
-
-  while pos < buf.len() {
-    if s.is_empty() {
-      panic!("TODO");
-      // return Err(Error::new(ErrorKind::UnexpectedEof, "buffer too small"));
-    }
-
-    let chunk_size = std::cmp::min(s.len(), buf.len() - pos);
-    buf[pos..pos + chunk_size].copy_from_slice(&s[..chunk_size]);
-    s.drain(..chunk_size);
-    pos += chunk_size;
-  }
-
-  Ok(())
-}
+// Every message is framed by its length, 4 bytes big endian.
+const HEADER_SIZE: usize = 4;
+/// Guards against allocating for a corrupt length, a frame holds at most a checkpoint or a log.
+pub const FRAME_SIZE_MAX: usize = 1 << 30;
+/// Limit on frames from a client, enough for a request with the largest key and value accepted.
+pub const CLIENT_FRAME_SIZE_MAX: usize = KEY_SIZE_MAX + VALUE_SIZE_MAX + 4096;
+/// Limit on frames from a peer that has not identified itself yet, enough for the handshake.
+pub const HANDSHAKE_SIZE_MAX: usize = 1024;
 
 fn write_all(s: &mut TcpStream, buf: &[u8]) -> Result<(), Error> {
   let mut pos = 0;
@@ -47,30 +37,104 @@ fn write_all(s: &mut TcpStream, buf: &[u8]) -> Result<(), Error> {
   Ok(())
 }
 
-pub fn read_message(s: &mut Vec<u8>) -> Result<IOMessage, Error> {
-  let mut header = [0u8; 4];
-  read_exact(s, &mut header)?;
+/// Reassembles the frames of a stream, which may split a frame across reads or deliver several
+/// at once.
+#[derive(Debug)]
+pub struct FrameBuffer {
+  buf: Vec<u8>,
+  pos: usize,      // start of the first frame not yet returned
+  size_max: usize, // larger frames are rejected before they are buffered
+}
 
-  let msg_size: usize = u32::from_be_bytes(header)
-    .try_into()
-    .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+impl Default for FrameBuffer {
+  fn default() -> Self {
+    FrameBuffer::with_size_max(FRAME_SIZE_MAX)
+  }
+}
 
-  let mut buf = vec![0u8; msg_size];
-  read_exact(s, &mut buf)?;
+impl FrameBuffer {
+  pub fn with_size_max(size_max: usize) -> Self {
+    FrameBuffer {
+      buf: Vec::new(),
+      pos: 0,
+      size_max,
+    }
+  }
 
-  bincode::deserialize(&buf).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+  pub fn set_size_max(&mut self, size_max: usize) {
+    self.size_max = size_max;
+  }
+
+  pub fn extend(&mut self, bytes: &[u8]) {
+    self.buf.drain(..self.pos);
+    self.pos = 0;
+    self.buf.extend_from_slice(bytes);
+  }
+
+  /// The next complete message, or None until more bytes arrive.
+  pub fn next_message(&mut self) -> Result<Option<IOMessage>, Error> {
+    let available = &self.buf[self.pos..];
+    let Some(header) = available.get(..HEADER_SIZE) else {
+      return Ok(None);
+    };
+    let msg_size = frame_size(header.try_into().unwrap(), self.size_max)?;
+    let Some(payload) = available.get(HEADER_SIZE..HEADER_SIZE + msg_size) else {
+      return Ok(None);
+    };
+
+    let msg = bincode::deserialize(payload).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    self.pos += HEADER_SIZE + msg_size;
+    Ok(Some(msg))
+  }
 }
 
-pub fn write_message(s: &mut TcpStream, msg: &IOMessage) -> Result<(), Error> {
+fn frame_size(header: [u8; HEADER_SIZE], size_max: usize) -> Result<usize, Error> {
+  let msg_size = u32::from_be_bytes(header) as usize;
+  if msg_size > size_max {
+    return Err(Error::new(
+      ErrorKind::InvalidData,
+      format!("frame of {} bytes exceeds the limit", msg_size),
+    ));
+  }
+  Ok(msg_size)
+}
+
+/// The message behind its length, as sent on the wire. Fails for messages larger than a frame
+/// may be, the receiver would reject them.
+pub fn encode_message(msg: &IOMessage) -> Result<Vec<u8>, Error> {
   let serialized = bincode::serialize(msg).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+  if serialized.len() > FRAME_SIZE_MAX {
+    return Err(Error::new(
+      ErrorKind::InvalidData,
+      format!(
+        "message of {} bytes exceeds the frame limit",
+        serialized.len()
+      ),
+    ));
+  }
 
   let header = (serialized.len() as u32).to_be_bytes();
 
-  let mut buf = Vec::with_capacity(serialized.len() + 4);
+  let mut buf = Vec::with_capacity(serialized.len() + HEADER_SIZE);
   buf.extend_from_slice(&header);
   buf.extend_from_slice(&serialized);
+  Ok(buf)
+}
+
+/// Blocks until a whole message has been read from `s`.
+pub fn recv_message(s: &mut TcpStream) -> Result<IOMessage, Error> {
+  let mut header = [0u8; HEADER_SIZE];
+  s.read_exact(&mut header)?;
+  let msg_size = frame_size(header, FRAME_SIZE_MAX)?;
 
-  write_all(s, &buf)
+  let mut buf = vec![0u8; msg_size];
+  s.read_exact(&mut buf)?;
+
+  bincode::deserialize(&buf).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+pub fn write_message(s: &mut TcpStream, msg: &IOMessage) -> Result<(), Error> {
+  write_all(s, &encode_message(msg)?)
 }
 
 // async fn handle_connection(
@@ -128,3 +192,74 @@ pub fn write_message(s: &mut TcpStream, msg: &IOMessage) -> Result<(), Error> {
 //   })
 //   .await;
 // }
+
+#[cfg(test)]
+mod tests {
+  use bytes::Bytes;
+
+  use super::*;
+  use crate::{message::ClientRequest, operation::Operation};
+
+  fn messages() -> Vec<IOMessage> {
+    (0..5)
+      .map(|n| {
+        IOMessage::Client(ClientRequest {
+          client_id: 1,
+          request_number: n,
+          op: Operation::Add {
+            key: Bytes::from("k"),
+            value: Bytes::from(vec![7u8; n * 3000]),
+          },
+        })
+      })
+      .collect()
+  }
+
+  #[test]
+  fn split_and_coalesced_frames() {
+    let msgs = messages();
+    let stream: Vec<u8> = msgs
+      .iter()
+      .flat_map(|msg| encode_message(msg).unwrap())
+      .collect();
+    // From a byte at a time to everything in one read.
+    for chunk in [1, 7, 1024, 5000, stream.len()] {
+      let mut frames = FrameBuffer::default();
+      let mut received = Vec::new();
+      for bytes in stream.chunks(chunk) {
+        frames.extend(bytes);
+        while let Some(msg) = frames.next_message().unwrap() {
+          received.push(msg);
+        }
+      }
+      assert_eq!(received, msgs, "reads of {} bytes", chunk);
+    }
+  }
+
+  #[test]
+  fn oversized_frame() {
+    let mut frames = FrameBuffer::default();
+    frames.extend(&((FRAME_SIZE_MAX + 1) as u32).to_be_bytes());
+    assert!(frames.next_message().is_err());
+  }
+
+  #[test]
+  fn size_max() {
+    let msg = encode_message(&messages()[1]).unwrap();
+    let mut frames = FrameBuffer::with_size_max(HANDSHAKE_SIZE_MAX);
+    frames.extend(&msg[..HEADER_SIZE]);
+    assert!(frames.next_message().is_err());
+
+    let mut frames = FrameBuffer::with_size_max(HANDSHAKE_SIZE_MAX);
+    frames.extend(&msg);
+    frames.set_size_max(FRAME_SIZE_MAX);
+    assert!(frames.next_message().unwrap().is_some());
+  }
+
+  #[test]
+  fn corrupt_frame() {
+    let mut frames = FrameBuffer::default();
+    frames.extend(&[0, 0, 0, 2, 0xff, 0xff]);
+    assert!(frames.next_message().is_err());
+  }
+}
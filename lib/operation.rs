@@ -2,17 +2,52 @@ use bytes::Bytes;
 use quickcheck::{Arbitrary, Gen};
 use serde::{Deserialize, Serialize};
 
+use crate::{error::OpError, types::OpNumber};
+
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub enum Operation {
   Add { key: Bytes, value: Bytes },
   Update { key: Bytes, value: Bytes },
   Remove { key: Bytes },
+  // Ordered through the log like any other op, so reads are linearizable.
+  Get { key: Bytes },
+  // Served by any replica from its committed state without going through the log, fails unless
+  // the replica has committed at least `min_op_number`.
+  GetStale { key: Bytes, min_op_number: OpNumber },
   Join,
 }
 
+/// Largest key accepted by any op, in bytes.
+pub const KEY_SIZE_MAX: usize = 1 << 10;
+/// Largest value accepted by Add and Update, in bytes.
+pub const VALUE_SIZE_MAX: usize = 1 << 20;
+
+impl Operation {
+  /// Checks that don't depend on the state of the store, done before the op enters the log.
+  pub fn validate(&self) -> Result<(), OpError> {
+    match self {
+      Operation::Add { key, .. }
+      | Operation::Update { key, .. }
+      | Operation::Remove { key }
+      | Operation::Get { key }
+      | Operation::GetStale { key, .. }
+        if key.len() > KEY_SIZE_MAX =>
+      {
+        Err(OpError::KeyTooLarge)
+      }
+      Operation::Add { value, .. } | Operation::Update { value, .. }
+        if value.len() > VALUE_SIZE_MAX =>
+      {
+        Err(OpError::ValueTooLarge)
+      }
+      _ => Ok(()),
+    }
+  }
+}
+
 impl Arbitrary for Operation {
   fn arbitrary(g: &mut Gen) -> Self {
-    match u8::arbitrary(g) % 4 {
+    match u8::arbitrary(g) % 6 {
       0 => Operation::Add {
         key: Bytes::from(Vec::<u8>::arbitrary(g)),
         value: Bytes::from(Vec::<u8>::arbitrary(g)),
@@ -24,6 +59,13 @@ impl Arbitrary for Operation {
       2 => Operation::Remove {
         key: Bytes::from(Vec::<u8>::arbitrary(g)),
       },
+      3 => Operation::Get {
+        key: Bytes::from(Vec::<u8>::arbitrary(g)),
+      },
+      4 => Operation::GetStale {
+        key: Bytes::from(Vec::<u8>::arbitrary(g)),
+        min_op_number: Arbitrary::arbitrary(g),
+      },
       _ => Operation::Join,
     }
   }
@@ -31,9 +73,11 @@ impl Arbitrary for Operation {
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub enum OpResult {
-  AddResult(Result<(), ()>),     // TODO: error type
-  UpdateResult(Result<(), ()>),  // TODO: error type
-  RemoveResult(Result<(), ()>),  // TODO: error type
-  JoinResult(Result<usize, ()>), // TODO: error type
+  AddResult(Result<(), OpError>),
+  UpdateResult(Result<(), OpError>),
+  RemoveResult(Result<(), OpError>),
+  GetResult(Result<Bytes, OpError>),
+  JoinResult(Result<usize, OpError>),
   Outdated,
+  Rejected(OpError), // the request was not executed
 }
@@ -1,20 +1,50 @@
-use std::{
-  collections::VecDeque,
-  net::{SocketAddr, TcpStream},
-};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 
 use log::debug;
 
 use crate::{
+  checkpoint::Checkpoint,
   client_table::ClienTable,
   configuration::Configuration,
+  error::OpError,
+  journal::{Record, Replayed},
+  kvstore::KVStore,
   log::Log,
-  message::{ClientRequest, IOMessage, Prepare, PrepareOk, ReplicaMessage, Reply},
-  network::{write_message, ConnectionTable},
-  operation::OpResult,
-  types::{CommitID, ConnectionID, ReplicaID, ViewNumber},
+  message::{
+    ClientRequest, Commit, DoViewChange, GetState, IOMessage, NewState, Prepare, PrepareOk,
+    Recovery, RecoveryResponse, ReplicaMessage, Reply, StartView, StartViewChange,
+  },
+  operation::{OpResult, Operation},
+  types::{ClientID, CommitID, Nonce, OpNumber, ReplicaID, Timestamp, ViewNumber},
 };
 
+/// Client requests the primary buffers while a prepare is in flight, beyond that they are
+/// rejected.
+const PENDING_REQUESTS_MAX: usize = 64;
+
+/// Where a message from the replica goes, delivering it is up to the caller.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Destination {
+  Replica(ReplicaID),
+  Client(ClientID),
+}
+
+/// What the replica needs on disk, in the order it was queued.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum JournalWrite {
+  Record(Record),
+  /// Replaces the snapshot, after which the journal is rewritten to `records`, which cover the
+  /// log past the checkpoint, followed by the records queued after it. A checkpoint of our own
+  /// log only covers ops the journal holds, so those records may be written before the snapshot
+  /// is durable. An installed one must be durable before them, they may skip over its ops.
+  Checkpoint {
+    view: ViewNumber,
+    checkpoint: Checkpoint,
+    records: Vec<Record>,
+    installed: bool,
+  },
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Status {
   Normal,
@@ -22,129 +52,1039 @@ pub enum Status {
   Recovering,
 }
 
+/// The protocol as a state machine without I/O, clocks or randomness. Its inputs are messages
+/// and ticks, its outputs the messages and journal records it queues for the caller.
 #[derive(Clone, Debug)]
 pub struct Replica {
   conf: Configuration,
   replica: ReplicaID, // This is the index into conf
   view: ViewNumber,   // view number, initially 0
+  last_normal_view: ViewNumber,
   status: Status,
   log: Log,
   commit: CommitID, // commit number, the most recent committed op_number
   client_table: ClienTable,
-  // reached_consensus: HashMap<OpNumber, HashSet<ReplicaID, usize>>,
-  // store: KVStore,
-  client_sessions: ConnectionTable,
-  replica_tx: VecDeque<(ReplicaID, ReplicaMessage)>,
+  reached_consensus: BTreeMap<OpNumber, BTreeSet<ReplicaID>>, // backups that sent PrepareOk per op
+  start_view_changes: BTreeMap<ReplicaID, CommitID>,          // commit number of each sender
+  do_view_changes: BTreeMap<ReplicaID, DoViewChange>,
+  idle_ticks: usize, // ticks since the primary last sent (primary) or was heard from (backup)
+  prepare_ticks: usize, // ticks the primary has waited for PrepareOks without progress
+  now: Timestamp,
+  lease_grants: BTreeMap<ReplicaID, Timestamp>, // latest lease granted by each backup
+  lease_granted_until: Timestamp, // promise to the primary, no view change before this
+  view_start_op: OpNumber,        // ops up to here might have been committed in earlier views
+  do_view_change_sent: bool,
+  nonce: Nonce, // identifies the responses to our latest Recovery
+  recovery_responses: BTreeMap<ReplicaID, RecoveryResponse>,
+  store: KVStore,
+  checkpoint: Checkpoint,
+  pending_requests: VecDeque<ClientRequest>, // waiting for the prepare in flight to commit
+  outbox: VecDeque<(Destination, IOMessage)>,
+  journal_tx: VecDeque<JournalWrite>, // must be durable before any queued message is sent
 }
 
 impl Replica {
-  pub fn new(conf: Configuration, replica: ReplicaID, client_sessions: ConnectionTable) -> Self {
+  pub fn new(conf: Configuration, replica: ReplicaID) -> Self {
     Replica {
       conf,
       replica,
       view: 0,
+      last_normal_view: 0,
       status: Status::Normal,
       commit: 0,
       log: Log::default(),
       client_table: ClienTable::default(),
-      // reached_consensus: HashMap::default(),
-      // store: KVStore::default(),
-      client_sessions,
-      replica_tx: VecDeque::default(),
+      reached_consensus: BTreeMap::default(),
+      start_view_changes: BTreeMap::default(),
+      do_view_changes: BTreeMap::default(),
+      idle_ticks: 0,
+      prepare_ticks: 0,
+      now: 0,
+      lease_grants: BTreeMap::default(),
+      lease_granted_until: 0,
+      view_start_op: 0,
+      do_view_change_sent: false,
+      nonce: 0,
+      recovery_responses: BTreeMap::default(),
+      store: KVStore::default(),
+      checkpoint: Checkpoint::default(),
+      pending_requests: VecDeque::default(),
+      outbox: VecDeque::default(),
+      journal_tx: VecDeque::default(),
     }
   }
 
-  pub fn on_client_request(&mut self, req: ClientRequest, conn_id: ConnectionID) {
-    assert!(self.is_primary());
-    assert_eq!(self.status, Status::Normal);
+  /// A replica that restarts after a crash has lost its state, it must not take part in the
+  /// protocol until it has learned the current view and log from the other replicas. The nonce
+  /// must not have been used by an earlier recovery of this replica.
+  pub fn recovering(conf: Configuration, replica: ReplicaID, nonce: Nonce) -> Self {
+    let mut r = Replica::new(conf, replica);
+    r.status = Status::Recovering;
+    r.nonce = nonce;
+    // A lease granted right before crashing must still be honored.
+    r.lease_granted_until = r.conf.timeouts.lease;
+    r.broadcast_recovery();
+    r
+  }
+
+  /// A replica restarting from its journal still has its log and latest checkpoint, but not the
+  /// commit number past the checkpoint or whether other replicas moved on. It waits in a view
+  /// change for the primary, whose next Prepare or Commit has it fetch the log of the view. Only
+  /// without word from a primary does it move on to the next view, which covers restarting the
+  /// whole cluster.
+  pub fn restart(conf: Configuration, replica: ReplicaID, replayed: Replayed) -> Self {
+    let mut r = Replica::new(conf, replica);
+    r.log = replayed.log;
+    if replayed.checkpoint.op_number > 0 {
+      r.install_checkpoint(replayed.checkpoint);
+    }
+    r.view = replayed.view;
+    r.last_normal_view = replayed.last_normal_view;
+    r.status = Status::ViewChange;
+    r.lease_granted_until = r.conf.timeouts.lease;
+    r
+  }
+
+  pub fn on_client_request(&mut self, req: ClientRequest) {
+    if self.status != Status::Normal {
+      self.reject(&req, OpError::NotNormal);
+    } else if let Operation::GetStale { min_op_number, .. } = req.op {
+      if self.commit < min_op_number {
+        self.reject(&req, OpError::PreconditionFailed);
+      } else {
+        self.read_locally(req);
+      }
+    } else if self.is_backup() {
+      self.reject(&req, OpError::NotPrimary);
+    } else if self.is_duplicate(&req) {
+      debug!("Dropping duplicate {:?}", req);
+    } else if let Err(err) = req.op.validate() {
+      self.reject(&req, err);
+    } else if matches!(req.op, Operation::Get { .. }) && self.has_read_lease() {
+      self.read_locally(req);
+    } else if self.log.last_op_number() > self.commit {
+      if self.pending_requests.len() >= PENDING_REQUESTS_MAX {
+        self.reject(&req, OpError::QueueFull);
+      } else {
+        self.pending_requests.push_back(req);
+      }
+    } else {
+      self.prepare(req);
+    }
+  }
 
-    self.client_sessions.insert(req.client_id, conn_id);
+  /// A request is only executed if it is newer than anything seen from the client so far. When
+  /// the client retries its latest request and it has been executed, the cached reply is resent.
+  fn is_duplicate(&mut self, req: &ClientRequest) -> bool {
+    let seen =
+      |r: &ClientRequest| r.client_id == req.client_id && r.request_number >= req.request_number;
+    // After a view change the uncommitted ops in the log are in progress as well.
+    let in_progress = (self.commit + 1..=self.log.last_op_number())
+      .filter_map(|op_number| self.log.get(op_number))
+      .any(seen);
+    if in_progress || self.pending_requests.iter().any(seen) {
+      return true;
+    }
 
-    let last_op_num = self.log.append(self.view, req.clone());
-    self.client_table.insert(req.client_id, req.request_number);
+    let Some(entry) = self.client_table.find_client(req.client_id) else {
+      return false;
+    };
+    if req.request_number > entry.last_request_id {
+      return false;
+    }
+    if req.request_number == entry.last_request_id {
+      if let Some(result) = entry.last_result.clone() {
+        let op_number = entry.last_op_number;
+        self.reply(
+          req.client_id,
+          Reply {
+            view_number: self.view,
+            request_number: req.request_number,
+            op_number,
+            result,
+          },
+        );
+      }
+    }
+    true
+  }
 
-    self.broadcast_prepare(Prepare {
+  /// While the backups promise not to elect another primary, no other replica can commit ops, so
+  /// the primary can serve reads from its own store without going through the log.
+  fn has_read_lease(&self) -> bool {
+    if self.status != Status::Normal || self.is_backup() || self.commit < self.view_start_op {
+      return false;
+    }
+    let timeouts = &self.conf.timeouts;
+    let granted = self
+      .lease_grants
+      .values()
+      .filter(|&&timestamp| self.now + timeouts.lease_margin < timestamp + timeouts.lease)
+      .count();
+    granted + 1 >= self.conf.quorum()
+  }
+
+  fn read_locally(&mut self, req: ClientRequest) {
+    let (Operation::Get { key } | Operation::GetStale { key, .. }) = req.op else {
+      unreachable!("only reads are served locally");
+    };
+    let result = OpResult::GetResult(self.store.get(&key).cloned().ok_or(OpError::NotFound));
+    self.reply(
+      req.client_id,
+      Reply {
+        view_number: self.view,
+        request_number: req.request_number,
+        op_number: self.commit,
+        result,
+      },
+    );
+  }
+
+  fn prepare(&mut self, req: ClientRequest) {
+    let last_op_num = self.append(req.clone());
+    self.reached_consensus.insert(last_op_num, BTreeSet::new());
+
+    self.idle_ticks = 0;
+    self.prepare_ticks = 0;
+    self.broadcast(ReplicaMessage::Prepare(Prepare {
       view_number: self.view,
       request: req,
       op_number: last_op_num,
       commit_number: self.commit,
-    });
+      timestamp: self.now,
+    }));
   }
 
   pub fn on_replica_message(&mut self, msg: ReplicaMessage) {
+    if self.status == Status::Recovering && !matches!(msg, ReplicaMessage::RecoveryResponse(_)) {
+      debug!("Ignoring {:?} while recovering", msg);
+      return;
+    }
+
     match msg {
-      ReplicaMessage::Prepare(prepare) => todo!(),
-      ReplicaMessage::PrepareOk(ok) => todo!(),
+      ReplicaMessage::Prepare(prepare) => self.on_prepare(prepare),
+      ReplicaMessage::PrepareOk(ok) => self.on_prepare_ok(ok),
+      ReplicaMessage::Commit(commit) => self.on_commit(commit),
+      ReplicaMessage::StartViewChange(svc) => self.on_start_view_change(svc),
+      ReplicaMessage::DoViewChange(dvc) => self.on_do_view_change(dvc),
+      ReplicaMessage::StartView(sv) => self.on_start_view(sv),
+      ReplicaMessage::Recovery(recovery) => self.on_recovery(recovery),
+      ReplicaMessage::RecoveryResponse(resp) => self.on_recovery_response(resp),
+      ReplicaMessage::GetState(gs) => self.on_get_state(gs),
+      ReplicaMessage::NewState(ns) => self.on_new_state(ns),
     }
   }
 
-  // fn on_prepare(&mut self, prepare: Prepare) {
-  //   assert!(self.is_backup());
+  /// Drives the timeouts, the event loop is expected to call this at a fixed interval.
+  pub fn tick(&mut self) {
+    self.idle_ticks += 1;
+    self.now += 1;
+    match self.status {
+      Status::Normal if self.is_primary() => {
+        if self.idle_ticks >= self.conf.timeouts.heartbeat {
+          self.idle_ticks = 0;
+          self.broadcast(ReplicaMessage::Commit(Commit {
+            view_number: self.view,
+            commit_number: self.commit,
+            timestamp: self.now,
+          }));
+        }
+        if self.log.last_op_number() == self.commit {
+          self.prepare_ticks = 0;
+        } else {
+          self.prepare_ticks += 1;
+          if self.prepare_ticks >= self.conf.timeouts.prepare_retransmit {
+            self.retransmit_prepares();
+          }
+        }
+      }
+      Status::Normal | Status::ViewChange => {
+        if self.idle_ticks >= self.conf.timeouts.view_change {
+          self.start_view_change(self.view + 1);
+        } else {
+          self.progress_view_change();
+        }
+      }
+      Status::Recovering => {
+        if self.idle_ticks >= self.conf.timeouts.recovery {
+          self.broadcast_recovery();
+        }
+      }
+    }
+  }
 
-  //   self.log.append(self.view, prepare.request);
-  //   self.commit_ops(prepare.commit_number);
+  /// Resends the uncommitted ops to the backups that have not acknowledged them, either the
+  /// Prepare or the PrepareOk was lost. Backups ack a Prepare they already have again.
+  fn retransmit_prepares(&mut self) {
+    self.prepare_ticks = 0;
+    for op_number in self.commit + 1..=self.log.last_op_number() {
+      let (Some(oks), Some(request)) = (
+        self.reached_consensus.get(&op_number),
+        self.log.get(op_number),
+      ) else {
+        continue;
+      };
+      for replica in 0..self.conf.replicas.len() {
+        if replica == self.replica || oks.contains(&replica) {
+          continue;
+        }
+        debug!("Retransmitting op {} to replica {}", op_number, replica);
+        let prepare = ReplicaMessage::Prepare(Prepare {
+          view_number: self.view,
+          request: request.clone(),
+          op_number,
+          commit_number: self.commit,
+          timestamp: self.now,
+        });
+        self
+          .outbox
+          .push_back((Destination::Replica(replica), IOMessage::Replica(prepare)));
+      }
+    }
+  }
 
-  //   let primary = self.conf.primary_id(self.view);
+  fn on_prepare(&mut self, prepare: Prepare) {
+    if !self.on_primary_message(prepare.view_number) {
+      debug!(
+        "Ignoring {:?} in view {} ({:?})",
+        prepare, self.view, self.status
+      );
+      return;
+    }
 
-  //   self.replica_tx.push_back((
-  //     self.conf.find_addr(primary),
-  //     ReplicaMessage::PrepareOk(PrepareOk {
-  //       view_number: self.view,
-  //       op_number: prepare.op_number,
-  //       replica_number: self.replica,
-  //     }),
-  //   ));
-  // }
+    let last_op_num = self.log.last_op_number();
+    if prepare.op_number > last_op_num + 1 {
+      debug!("Missing ops {}..{}", last_op_num + 1, prepare.op_number);
+      self.request_state();
+      return;
+    }
+    // A prepare we already have means our PrepareOk was lost, ack it again.
+    if prepare.op_number == last_op_num + 1 {
+      self.append(prepare.request);
+    }
 
-  // fn on_prepare_ok(&mut self, ok: PrepareOk) {
-  //   assert!(self.is_primary());
-  //   // TODO - state update
+    self.grant_lease(prepare.op_number, prepare.timestamp);
+    self.commit_ops(prepare.commit_number);
+  }
 
-  //   let r = Reply {
-  //     view_number: ok.view_number,
-  //     request_number: 0, // TODO
-  //     result: OpResult::Outdated,
-  //   };
+  fn on_prepare_ok(&mut self, ok: PrepareOk) {
+    if self.status != Status::Normal || ok.view_number != self.view {
+      debug!(
+        "Ignoring {:?} in view {} ({:?})",
+        ok, self.view, self.status
+      );
+      return;
+    }
+    assert!(self.is_primary());
 
-  //   // TODO qourum
-  //   // see that majority is reached through PrepareOk
+    if let Some(timestamp) = ok.timestamp {
+      let granted = self.lease_grants.entry(ok.replica_number).or_default();
+      *granted = timestamp.max(*granted);
+    }
+
+    // Backups append in order, so a PrepareOk for n also acknowledges every op before n.
+    for op_number in self.commit + 1..=ok.op_number {
+      if let Some(oks) = self.reached_consensus.get_mut(&op_number) {
+        oks.insert(ok.replica_number);
+      }
+    }
+
+    // The primary itself counts towards the quorum.
+    let mut commit = self.commit;
+    while let Some(oks) = self.reached_consensus.get(&(commit + 1)) {
+      if oks.len() + 1 < self.conf.quorum() {
+        break;
+      }
+      commit += 1;
+    }
+    if commit > self.commit {
+      self.prepare_ticks = 0;
+    }
+    self.commit_ops(commit);
 
-  //   // TODO commit
-  //   let req = &self.log.entries[self.commit];
-  //   debug!("PrepareOk {:?}", req);
+    if self.log.last_op_number() == self.commit {
+      if let Some(req) = self.pending_requests.pop_front() {
+        self.prepare(req);
+      }
+    }
+  }
 
-  //   // TODO, now time to "send" reply
+  fn on_commit(&mut self, commit: Commit) {
+    if !self.on_primary_message(commit.view_number) {
+      debug!(
+        "Ignoring {:?} in view {} ({:?})",
+        commit, self.view, self.status
+      );
+      return;
+    }
 
-  //   // self.client_tx.push_back(r);
-  //   // let mut binding = self.client_sessions.lock().unwrap();
-  //   // let x = binding.get_mut(&req.client_id).unwrap();
+    if commit.commit_number > self.log.last_op_number() {
+      self.request_state();
+    }
+    self.grant_lease(self.log.last_op_number(), commit.timestamp);
+    self.commit_ops(commit.commit_number);
+  }
 
-  //   write_message(x, &IOMessage::Reply(r)).unwrap();
-  // }
+  /// Acknowledges the ops up to `op_number` and grants the primary a read lease from
+  /// `timestamp`, its time of sending.
+  fn grant_lease(&mut self, op_number: OpNumber, timestamp: Timestamp) {
+    self.lease_granted_until = self.now + self.conf.timeouts.lease;
+    self.send_to_primary(ReplicaMessage::PrepareOk(PrepareOk {
+      view_number: self.view,
+      op_number,
+      replica_number: self.replica,
+      timestamp: Some(timestamp),
+    }));
+  }
+
+  /// Checks the view of a Prepare or Commit, which only the primary of that view sends. Returns
+  /// whether the message should be processed, a replica that missed the view change leading up
+  /// to the message fetches the log of the primary first.
+  fn on_primary_message(&mut self, view: ViewNumber) -> bool {
+    if view < self.view {
+      return false;
+    }
+    if view > self.view || self.status != Status::Normal {
+      // Ops after the commit number may not have survived into the new view. They are only
+      // replaced once the primary sent its log, until then ours might be the only copy of some.
+      debug!(
+        "Catching up from view {} ({:?}) to view {} after op {}",
+        self.view, self.status, view, self.commit
+      );
+      if view > self.view {
+        self.view = view;
+        self.status = Status::ViewChange;
+        self.reached_consensus.clear();
+        self.start_view_changes.clear();
+        self.do_view_changes.clear();
+        self.do_view_change_sent = true;
+        self.lease_grants.clear();
+        self.reject_pending();
+        self.journal(Record::ViewChange { view_number: view });
+      }
+      self.idle_ticks = 0;
+      self.send_to_primary(ReplicaMessage::GetState(GetState {
+        view_number: view,
+        op_number: self.commit,
+        replica_number: self.replica,
+      }));
+      return false;
+    }
+    assert!(self.is_backup());
+    self.idle_ticks = 0;
+    true
+  }
+
+  /// Asks for the ops after our log, or after our commit number if the log starts past it.
+  fn request_state(&mut self) {
+    let op_number = if self.is_missing_ops() {
+      self.commit
+    } else {
+      self.log.last_op_number()
+    };
+    let gs = ReplicaMessage::GetState(GetState {
+      view_number: self.view,
+      op_number,
+      replica_number: self.replica,
+    });
+    // A new primary may have installed a log that starts past its commit number, any backup in
+    // the view can fill in the ops in between.
+    if self.is_primary() {
+      self.broadcast(gs);
+    } else {
+      self.send_to_primary(gs);
+    }
+  }
+
+  /// Whether ops up to our commit number are neither executed nor in the log, which happens
+  /// after installing a log that another replica already discarded a prefix of.
+  fn is_missing_ops(&self) -> bool {
+    self.commit + 1 < self.log.first_op_number()
+  }
+
+  fn on_get_state(&mut self, gs: GetState) {
+    if self.status != Status::Normal || gs.view_number != self.view {
+      debug!(
+        "Ignoring {:?} in view {} ({:?})",
+        gs, self.view, self.status
+      );
+      return;
+    }
+
+    let checkpoint = if gs.op_number + 1 >= self.log.first_op_number() {
+      None
+    } else if self.checkpoint.op_number + 1 >= self.log.first_op_number() {
+      Some(self.checkpoint.clone())
+    } else if !self.is_missing_ops() {
+      // Our log was replaced by one that starts past our checkpoint, a checkpoint of what we
+      // executed covers the ops in between.
+      self.take_checkpoint();
+      Some(self.checkpoint.clone())
+    } else {
+      debug!("Missing ops ourselves, ignoring {:?}", gs);
+      return;
+    };
+    let op_number = checkpoint.as_ref().map_or(gs.op_number, |cp| cp.op_number);
+
+    self.send(
+      gs.replica_number,
+      ReplicaMessage::NewState(NewState {
+        view_number: self.view,
+        checkpoint,
+        log: self.log.suffix(op_number),
+        op_number: self.log.last_op_number(),
+        commit_number: self.commit,
+      }),
+    );
+  }
+
+  /// Continues our log with the ops of another replica. A backup catching up on the view of the
+  /// primary replaces the ops after its commit number instead, and enters normal operation.
+  fn on_new_state(&mut self, ns: NewState) {
+    let catching_up = self.status == Status::ViewChange && self.is_backup();
+    if !(self.status == Status::Normal || catching_up) || ns.view_number != self.view {
+      debug!(
+        "Ignoring NewState for view {} in view {} ({:?})",
+        ns.view_number, self.view, self.status
+      );
+      return;
+    }
+    if let Some(checkpoint) = ns.checkpoint {
+      if checkpoint.op_number > self.commit {
+        self.install_checkpoint(checkpoint);
+        self.persist_checkpoint(true);
+      }
+    }
+
+    let mut log = self.log.clone();
+    if catching_up {
+      log.truncate(self.commit);
+    }
+    if !log.merge(ns.log) {
+      debug!("NewState does not continue our log, ignoring");
+      return;
+    }
+    self.install_log(log);
+    if catching_up {
+      self.status = Status::Normal;
+      self.last_normal_view = self.view;
+      self.idle_ticks = 0;
+    }
+    if self.is_backup() && self.log.last_op_number() > self.commit.max(ns.commit_number) {
+      self.send_to_primary(ReplicaMessage::PrepareOk(PrepareOk {
+        view_number: self.view,
+        op_number: self.log.last_op_number(),
+        replica_number: self.replica,
+        timestamp: None,
+      }));
+    }
+    self.commit_ops(ns.commit_number);
+  }
+
+  fn start_view_change(&mut self, view: ViewNumber) {
+    debug!("Starting view change from view {} to {}", self.view, view);
+    self.view = view;
+    self.status = Status::ViewChange;
+    self.idle_ticks = 0;
+    self.reached_consensus.clear();
+    self.start_view_changes.clear();
+    self.do_view_changes.clear();
+    self.do_view_change_sent = false;
+    self.lease_grants.clear();
+    self.reject_pending();
+    self.journal(Record::ViewChange { view_number: view });
+
+    self.broadcast(ReplicaMessage::StartViewChange(StartViewChange {
+      view_number: view,
+      commit_number: self.commit,
+      replica_number: self.replica,
+    }));
+  }
+
+  fn on_start_view_change(&mut self, svc: StartViewChange) {
+    if svc.view_number < self.view
+      || (svc.view_number == self.view && self.status != Status::ViewChange)
+    {
+      debug!(
+        "Ignoring {:?} in view {} ({:?})",
+        svc, self.view, self.status
+      );
+      return;
+    }
+    if svc.view_number > self.view {
+      self.start_view_change(svc.view_number);
+    }
+
+    self
+      .start_view_changes
+      .insert(svc.replica_number, svc.commit_number);
+    self.progress_view_change();
+  }
+
+  /// Once f other replicas agree on the view change, hand our state to the new primary, which
+  /// starts the view after receiving f + 1 DoViewChange messages. Neither happens while a lease
+  /// we granted to the previous primary might still be in use. A backup also waits for the
+  /// StartViewChange of the new primary, its commit number tells whether to include our
+  /// checkpoint.
+  fn progress_view_change(&mut self) {
+    if self.status != Status::ViewChange || self.now < self.lease_granted_until {
+      return;
+    }
+
+    let primary_commit = if self.is_primary() {
+      Some(self.commit)
+    } else {
+      let primary = self.conf.primary_id(self.view);
+      self.start_view_changes.get(&primary).copied()
+    };
+    if !self.do_view_change_sent
+      && self.start_view_changes.len() + 1 >= self.conf.quorum()
+      && primary_commit.is_some()
+    {
+      self.do_view_change_sent = true;
+      let dvc = DoViewChange {
+        view_number: self.view,
+        checkpoint: primary_commit.and_then(|commit| self.checkpoint_for(commit)),
+        log: self.log.clone(),
+        last_normal_view: self.last_normal_view,
+        op_number: self.log.last_op_number(),
+        commit_number: self.commit,
+        replica_number: self.replica,
+      };
+      if self.is_primary() {
+        self.do_view_changes.insert(self.replica, dvc);
+      } else {
+        self.send_to_primary(ReplicaMessage::DoViewChange(dvc));
+      }
+    }
+
+    if self.is_primary() && self.do_view_changes.len() >= self.conf.quorum() {
+      self.start_view();
+    }
+  }
+
+  fn on_do_view_change(&mut self, dvc: DoViewChange) {
+    if dvc.view_number < self.view
+      || (dvc.view_number == self.view && self.status != Status::ViewChange)
+    {
+      debug!(
+        "Ignoring DoViewChange for view {} from {} in view {} ({:?})",
+        dvc.view_number, dvc.replica_number, self.view, self.status
+      );
+      return;
+    }
+    if dvc.view_number > self.view {
+      self.start_view_change(dvc.view_number);
+    }
+    assert!(self.is_primary());
+
+    self.do_view_changes.insert(dvc.replica_number, dvc);
+    self.progress_view_change();
+  }
+
+  /// Installs the most up to date log among the collected DoViewChange messages, that is the one
+  /// with the largest last normal view and, among those, the largest op number. Logs of the same
+  /// last normal view agree on the ops they share, so the entries the chosen log already
+  /// discarded are taken from the others. If they don't reach back to our commit number, the
+  /// latest checkpoint among the messages covers them.
+  fn start_view(&mut self) {
+    let commit = self
+      .do_view_changes
+      .values()
+      .map(|dvc| dvc.commit_number)
+      .max()
+      .unwrap_or(self.commit);
+    let mut commits = std::mem::take(&mut self.start_view_changes);
+    for dvc in self.do_view_changes.values() {
+      commits.insert(dvc.replica_number, dvc.commit_number);
+    }
+    let dvcs: Vec<DoViewChange> = std::mem::take(&mut self.do_view_changes)
+      .into_values()
+      .collect();
+    let best = dvcs
+      .iter()
+      .max_by_key(|dvc| (dvc.last_normal_view, dvc.op_number))
+      .expect("quorum of DoViewChange");
+    let (last_normal_view, op_number) = (best.last_normal_view, best.op_number);
+    let mut log = best.log.clone();
+    let mut checkpoint: Option<Checkpoint> = None;
+    for dvc in dvcs {
+      if dvc.last_normal_view == last_normal_view {
+        log.merge(dvc.log);
+      }
+      if dvc.checkpoint.as_ref().map(|cp| cp.op_number) > checkpoint.as_ref().map(|cp| cp.op_number)
+      {
+        checkpoint = dvc.checkpoint;
+      }
+    }
+
+    debug!(
+      "Starting view {} with op number {} and commit {}",
+      self.view, op_number, commit
+    );
+    self.install_log(log);
+    self.status = Status::Normal;
+    self.last_normal_view = self.view;
+    if let Some(checkpoint) = checkpoint {
+      self.catch_up(checkpoint);
+    }
+    self.view_start_op = self.log.last_op_number();
+    self.idle_ticks = 0;
+
+    // Ops past the commit number still need PrepareOks in this view, which the backups send
+    // upon installing the StartView.
+    for op_number in self.commit + 1..=self.log.last_op_number() {
+      self.reached_consensus.insert(op_number, BTreeSet::new());
+    }
+
+    // Backups we don't know the commit number of fetch the checkpoint through GetState if needed.
+    for replica in 0..self.conf.replicas.len() {
+      if replica == self.replica {
+        continue;
+      }
+      let checkpoint = commits
+        .get(&replica)
+        .and_then(|&commit| self.checkpoint_for(commit));
+      self.send(
+        replica,
+        ReplicaMessage::StartView(StartView {
+          view_number: self.view,
+          checkpoint,
+          log: self.log.clone(),
+          op_number: self.log.last_op_number(),
+          commit_number: commit,
+        }),
+      );
+    }
+    self.commit_ops(commit);
+  }
+
+  fn on_start_view(&mut self, sv: StartView) {
+    if sv.view_number < self.view || (sv.view_number == self.view && self.status == Status::Normal)
+    {
+      debug!(
+        "Ignoring StartView for view {} in view {} ({:?})",
+        sv.view_number, self.view, self.status
+      );
+      return;
+    }
 
+    self.view = sv.view_number;
+    self.install_log(sv.log);
+    self.status = Status::Normal;
+    self.last_normal_view = self.view;
+    if let Some(checkpoint) = sv.checkpoint {
+      self.catch_up(checkpoint);
+    }
+    self.idle_ticks = 0;
+    self.start_view_changes.clear();
+    self.do_view_changes.clear();
+    assert!(self.is_backup());
+
+    if sv.op_number > sv.commit_number {
+      self.send_to_primary(ReplicaMessage::PrepareOk(PrepareOk {
+        view_number: self.view,
+        op_number: sv.op_number,
+        replica_number: self.replica,
+        timestamp: None,
+      }));
+    }
+    self.commit_ops(sv.commit_number);
+  }
+
+  fn broadcast_recovery(&mut self) {
+    self.idle_ticks = 0;
+    self.broadcast(ReplicaMessage::Recovery(Recovery {
+      replica_number: self.replica,
+      nonce: self.nonce,
+    }));
+  }
+
+  fn on_recovery(&mut self, recovery: Recovery) {
+    if self.status != Status::Normal {
+      debug!("Ignoring {:?} in {:?}", recovery, self.status);
+      return;
+    }
+
+    let log = if self.is_primary() {
+      Some(self.log.clone())
+    } else {
+      None
+    };
+    self.send(
+      recovery.replica_number,
+      ReplicaMessage::RecoveryResponse(RecoveryResponse {
+        view_number: self.view,
+        nonce: recovery.nonce,
+        log,
+        op_number: self.log.last_op_number(),
+        commit_number: self.commit,
+        replica_number: self.replica,
+      }),
+    );
+  }
+
+  /// Recovery completes once f + 1 replicas responded, one of them being the primary of the
+  /// latest view among the responses.
+  fn on_recovery_response(&mut self, resp: RecoveryResponse) {
+    if self.status != Status::Recovering || resp.nonce != self.nonce {
+      debug!("Ignoring RecoveryResponse from {}", resp.replica_number);
+      return;
+    }
+
+    self.recovery_responses.insert(resp.replica_number, resp);
+    if self.recovery_responses.len() < self.conf.quorum() {
+      return;
+    }
+
+    let view = self
+      .recovery_responses
+      .values()
+      .map(|resp| resp.view_number)
+      .max()
+      .expect("quorum of RecoveryResponse");
+    let primary = self.conf.primary_id(view);
+    let from_primary = self.recovery_responses.get(&primary);
+    if !from_primary.is_some_and(|resp| resp.view_number == view && resp.log.is_some()) {
+      debug!("Waiting for a RecoveryResponse from primary {}", primary);
+      return;
+    }
+    let resp = self
+      .recovery_responses
+      .remove(&primary)
+      .expect("checked above");
+
+    debug!(
+      "Recovered in view {} with op number {} and commit {}",
+      view, resp.op_number, resp.commit_number
+    );
+    self.view = view;
+    self.last_normal_view = view;
+    self.install_log(resp.log.expect("primary includes its log"));
+    self.status = Status::Normal;
+    self.idle_ticks = 0;
+    self.recovery_responses.clear();
+    self.commit_ops(resp.commit_number);
+  }
+
+  fn append(&mut self, req: ClientRequest) -> OpNumber {
+    let op_number = self.log.append(self.view, req);
+    self.journal_entries(op_number - 1);
+    op_number
+  }
+
+  /// Replaces the log upon entering normal operation in the current view. Only the entries that
+  /// differ from the old log are journaled.
+  fn install_log(&mut self, log: Log) {
+    let end = self.log.last_op_number().min(log.last_op_number());
+    // Committed ops are the same in every log.
+    let mut common = self.commit.min(end);
+    while common < end && self.log.get(common + 1) == log.get(common + 1) {
+      common += 1;
+    }
+
+    self.log = log;
+    self.journal(Record::View {
+      view_number: self.view,
+      op_number: common,
+    });
+    // A log starting past the ops we share continues after a checkpoint we might not have.
+    if self.log.first_op_number() > common + 1 {
+      self.journal(Record::View {
+        view_number: self.view,
+        op_number: self.log.first_op_number() - 1,
+      });
+    }
+    self.journal_entries(common);
+  }
+
+  fn take_checkpoint(&mut self) {
+    debug!("Checkpoint at op {}", self.commit);
+    self.checkpoint = Checkpoint {
+      op_number: self.commit,
+      store: self.store.clone(),
+      client_table: self.client_table.clone(),
+    };
+    self.log.compact(self.commit);
+    self.persist_checkpoint(false);
+  }
+
+  fn install_checkpoint(&mut self, checkpoint: Checkpoint) {
+    debug!(
+      "Installing checkpoint at op {}, commit was {}",
+      checkpoint.op_number, self.commit
+    );
+    self.commit = checkpoint.op_number;
+    self.store = checkpoint.store.clone();
+    self.client_table = checkpoint.client_table.clone();
+    self.log.compact(checkpoint.op_number);
+    self
+      .reached_consensus
+      .retain(|&op_number, _| op_number > self.commit);
+    self.checkpoint = checkpoint;
+  }
+
+  /// Our checkpoint, if a replica at `commit` can't continue from our log without it. A log
+  /// installed from another replica may start past our checkpoint, then it is of no use.
+  fn checkpoint_for(&self, commit: CommitID) -> Option<Checkpoint> {
+    let first_op_number = self.log.first_op_number();
+    (commit + 1 < first_op_number && self.checkpoint.op_number + 1 >= first_op_number)
+      .then(|| self.checkpoint.clone())
+  }
+
+  /// Installs the checkpoint that came along with a log starting past our commit number. The
+  /// ops it covers might not be executed anywhere else, so it is persisted right away.
+  fn catch_up(&mut self, checkpoint: Checkpoint) {
+    if self.is_missing_ops() && checkpoint.op_number + 1 >= self.log.first_op_number() {
+      self.install_checkpoint(checkpoint);
+      self.persist_checkpoint(true);
+    }
+  }
+
+  /// Journals the entries after `op_number`.
+  fn journal_entries(&mut self, op_number: OpNumber) {
+    let first_op_num = self.log.first_op_number();
+    for op_number in (op_number + 1).max(first_op_num)..=self.log.last_op_number() {
+      let request = self.log.get(op_number).expect("op within log").clone();
+      self.journal(Record::Entry {
+        view_number: self.view,
+        op_number,
+        request,
+      });
+    }
+  }
+
+  fn journal(&mut self, record: Record) {
+    self.journal_tx.push_back(JournalWrite::Record(record));
+  }
+
+  /// Queues the checkpoint for the snapshot, the journal only keeps the entries after it. The
+  /// checkpoint is only ever taken or installed in normal operation, so the entries belong to
+  /// the last normal view.
+  fn persist_checkpoint(&mut self, installed: bool) {
+    let op_number = self.checkpoint.op_number;
+    let mut records = vec![Record::View {
+      view_number: self.last_normal_view,
+      op_number,
+    }];
+    if self.view > self.last_normal_view {
+      records.push(Record::ViewChange {
+        view_number: self.view,
+      });
+    }
+    for op_number in op_number + 1..=self.log.last_op_number() {
+      let request = self.log.get(op_number).expect("op within log").clone();
+      records.push(Record::Entry {
+        view_number: self.last_normal_view,
+        op_number,
+        request,
+      });
+    }
+    self.journal_tx.push_back(JournalWrite::Checkpoint {
+      view: self.last_normal_view,
+      checkpoint: self.checkpoint.clone(),
+      records,
+      installed,
+    });
+  }
+
+  /// Executes every op up to `commit` that is present in the log, the primary also replies to
+  /// the client that issued the op.
   fn commit_ops(&mut self, commit: CommitID) {
+    let commit = commit.min(self.log.last_op_number());
+    if self.commit < commit && self.is_missing_ops() {
+      self.request_state();
+      return;
+    }
     while self.commit < commit {
       self.commit += 1;
-      let req = &self.log.entries[self.commit];
-      match req.op {
-        crate::operation::Operation::Join => {
-          // self.client_table.add_client();
-        }
-        _ => todo!(), // crate::operation::Operation::Add { key, value } => todo!(),
-                      // crate::operation::Operation::Update { key, value } => todo!(),
-                      // crate::operation::Operation::Remove { key } => todo!(),
+      self.reached_consensus.remove(&self.commit);
+
+      let req = self
+        .log
+        .get(self.commit)
+        .expect("committed op in log")
+        .clone();
+      let result = self.execute(req.op);
+      debug!("Committed op {} from client {}", self.commit, req.client_id);
+
+      self.client_table.update_client(
+        req.client_id,
+        req.request_number,
+        self.commit,
+        result.clone(),
+      );
+      if self.is_primary() {
+        self.reply(
+          req.client_id,
+          Reply {
+            view_number: self.view,
+            request_number: req.request_number,
+            op_number: self.commit,
+            result,
+          },
+        );
+      }
+
+      if self.commit >= self.checkpoint.op_number + self.conf.checkpoint_ops {
+        self.take_checkpoint();
+      }
+    }
+  }
+
+  fn execute(&mut self, op: Operation) -> OpResult {
+    match op {
+      Operation::Add { key, value } => OpResult::AddResult(self.store.add(key, value)),
+      Operation::Update { key, value } => OpResult::UpdateResult(self.store.update(key, value)),
+      Operation::Remove { key } => OpResult::RemoveResult(self.store.remove(&key)),
+      Operation::Get { key } | Operation::GetStale { key, .. } => {
+        OpResult::GetResult(self.store.get(&key).cloned().ok_or(OpError::NotFound))
       }
+      Operation::Join => OpResult::JoinResult(Ok(self.commit)),
+    }
+  }
+
+  fn reject(&mut self, req: &ClientRequest, err: OpError) {
+    debug!("Rejecting {:?}: {:?}", req, err);
+    self.reply(
+      req.client_id,
+      Reply {
+        view_number: self.view,
+        request_number: req.request_number,
+        op_number: self.commit,
+        result: OpResult::Rejected(err),
+      },
+    );
+  }
+
+  fn reject_pending(&mut self) {
+    while let Some(req) = self.pending_requests.pop_front() {
+      self.reject(&req, OpError::NotNormal);
     }
   }
 
-  fn broadcast_prepare(&mut self, msg: Prepare) {
-    for (i, _) in self.conf.replicas.iter().enumerate() {
+  fn reply(&mut self, client_id: ClientID, reply: Reply) {
+    self
+      .outbox
+      .push_back((Destination::Client(client_id), IOMessage::Reply(reply)));
+  }
+
+  fn send(&mut self, replica: ReplicaID, msg: ReplicaMessage) {
+    self
+      .outbox
+      .push_back((Destination::Replica(replica), IOMessage::Replica(msg)));
+  }
+
+  fn send_to_primary(&mut self, msg: ReplicaMessage) {
+    self.send(self.conf.primary_id(self.view), msg);
+  }
+
+  fn broadcast(&mut self, msg: ReplicaMessage) {
+    for i in 0..self.conf.replicas.len() {
       if self.replica == i {
         continue;
       }
-      self
-        .replica_tx
-        .push_back((i, ReplicaMessage::Prepare(msg.clone())));
+      self.send(i, msg.clone());
     }
   }
 
@@ -156,7 +1096,279 @@ impl Replica {
     !self.is_primary()
   }
 
-  pub fn dequeue_replica_msg(&mut self) -> Option<(ReplicaID, ReplicaMessage)> {
-    self.replica_tx.pop_back()
+  pub fn view(&self) -> ViewNumber {
+    self.view
+  }
+
+  pub fn status(&self) -> &Status {
+    &self.status
+  }
+
+  pub fn commit_number(&self) -> CommitID {
+    self.commit
+  }
+
+  pub fn log(&self) -> &Log {
+    &self.log
+  }
+
+  /// Messages in the order they were sent.
+  pub fn dequeue_message(&mut self) -> Option<(Destination, IOMessage)> {
+    self.outbox.pop_front()
+  }
+
+  /// Records to append to the journal. They must be durable before any message or reply dequeued
+  /// afterwards is sent, a PrepareOk promises the op survives a crash.
+  pub fn dequeue_journal_write(&mut self) -> Option<JournalWrite> {
+    self.journal_tx.pop_front()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use bytes::Bytes;
+
+  use crate::operation::KEY_SIZE_MAX;
+
+  use super::*;
+
+  /// What a replica has on disk.
+  #[derive(Clone, Default)]
+  struct Disk {
+    records: Vec<Record>,
+    snapshot: Option<(ViewNumber, Checkpoint)>,
+  }
+
+  impl Disk {
+    fn replay(&self) -> Replayed {
+      let mut replayed = Replayed::default();
+      for record in &self.records {
+        replayed.apply(record.clone());
+      }
+      if let Some((view, checkpoint)) = &self.snapshot {
+        replayed.install_checkpoint(*view, checkpoint.clone());
+      }
+      replayed
+    }
+  }
+
+  struct Cluster {
+    conf: Configuration,
+    replicas: Vec<Replica>,
+    disks: Vec<Disk>,
+    replies: Vec<Reply>,
+    down: BTreeSet<ReplicaID>, // neither sends nor receives
+  }
+
+  impl Cluster {
+    fn new(n: usize) -> Self {
+      let addrs = (0..n)
+        .map(|i| format!("127.0.0.1:{}", 3000 + i))
+        .collect::<Vec<_>>();
+      let conf = Configuration::new(addrs.iter().map(String::as_str).collect());
+      Cluster {
+        replicas: (0..n).map(|i| Replica::new(conf.clone(), i)).collect(),
+        disks: vec![Disk::default(); n],
+        replies: Vec::new(),
+        down: BTreeSet::new(),
+        conf,
+      }
+    }
+
+    /// Delivers messages until there are none left.
+    fn pump(&mut self) {
+      loop {
+        let mut msgs = Vec::new();
+        for (i, replica) in self.replicas.iter_mut().enumerate() {
+          let disk = &mut self.disks[i];
+          while let Some(write) = replica.dequeue_journal_write() {
+            match write {
+              JournalWrite::Record(record) => disk.records.push(record),
+              JournalWrite::Checkpoint {
+                view,
+                checkpoint,
+                records,
+                ..
+              } => {
+                disk.snapshot = Some((view, checkpoint));
+                disk.records = records;
+              }
+            }
+          }
+          while let Some(out) = replica.dequeue_message() {
+            if !self.down.contains(&i) {
+              msgs.push(out);
+            }
+          }
+        }
+        if msgs.is_empty() {
+          return;
+        }
+        for (to, msg) in msgs {
+          match (to, msg) {
+            (Destination::Replica(to), IOMessage::Replica(msg)) if !self.down.contains(&to) => {
+              self.replicas[to].on_replica_message(msg)
+            }
+            (Destination::Client(_), IOMessage::Reply(reply)) => self.replies.push(reply),
+            _ => {}
+          }
+        }
+      }
+    }
+
+    fn tick(&mut self, ticks: usize) {
+      for _ in 0..ticks {
+        for replica in &mut self.replicas {
+          replica.tick();
+        }
+        self.pump();
+      }
+    }
+
+    fn request(&mut self, primary: ReplicaID, request_number: usize, op: Operation) -> Reply {
+      self.replicas[primary].on_client_request(ClientRequest {
+        client_id: 1,
+        request_number,
+        op,
+      });
+      self.pump();
+      self.replies.pop().expect("reply")
+    }
+  }
+
+  fn add(n: usize) -> Operation {
+    Operation::Add {
+      key: Bytes::from(format!("k{}", n)),
+      value: Bytes::from(format!("v{}", n)),
+    }
+  }
+
+  #[test]
+  fn pending_requests() {
+    let mut cluster = Cluster::new(3);
+    // Nothing is delivered yet, so the first prepare stays in flight while the rest queue up.
+    for n in 1..=PENDING_REQUESTS_MAX + 2 {
+      cluster.replicas[0].on_client_request(ClientRequest {
+        client_id: 1,
+        request_number: n,
+        op: add(n),
+      });
+    }
+    cluster.pump();
+
+    let (rejected, committed): (Vec<Reply>, Vec<Reply>) = cluster
+      .replies
+      .drain(..)
+      .partition(|reply| matches!(reply.result, OpResult::Rejected(_)));
+    assert_eq!(rejected.len(), 1);
+    assert_eq!(rejected[0].request_number, PENDING_REQUESTS_MAX + 2);
+    assert_eq!(rejected[0].result, OpResult::Rejected(OpError::QueueFull));
+    // The queued requests are prepared in the order they arrived, one after the other.
+    assert_eq!(committed.len(), PENDING_REQUESTS_MAX + 1);
+    for (i, reply) in committed.iter().enumerate() {
+      assert_eq!(reply.request_number, i + 1);
+      assert_eq!(reply.op_number, i + 1);
+      assert_eq!(reply.result, OpResult::AddResult(Ok(())));
+    }
+  }
+
+  #[test]
+  fn oversized_key() {
+    let mut cluster = Cluster::new(3);
+    let key = Bytes::from(vec![b'k'; KEY_SIZE_MAX + 1]);
+    let reply = cluster.request(0, 1, Operation::Remove { key: key.clone() });
+    assert_eq!(reply.result, OpResult::Rejected(OpError::KeyTooLarge));
+    assert_eq!(reply.op_number, 0);
+    let reply = cluster.request(0, 2, Operation::Get { key });
+    assert_eq!(reply.result, OpResult::Rejected(OpError::KeyTooLarge));
+  }
+
+  #[test]
+  fn get_stale() {
+    let mut cluster = Cluster::new(3);
+    cluster.request(0, 1, add(1));
+    cluster.request(0, 2, add(2));
+    // The backups learn about the second commit with the next Prepare or Commit.
+    assert_eq!(cluster.replicas[1].commit_number(), 1);
+
+    let get_stale = |n: usize, min_op_number| Operation::GetStale {
+      key: Bytes::from(format!("k{}", n)),
+      min_op_number,
+    };
+    let reply = cluster.request(1, 3, get_stale(1, 1));
+    assert_eq!(reply.result, OpResult::GetResult(Ok(Bytes::from("v1"))));
+    assert_eq!(reply.op_number, 1);
+    let reply = cluster.request(1, 4, get_stale(2, 0));
+    assert_eq!(reply.result, OpResult::GetResult(Err(OpError::NotFound)));
+    assert_eq!(reply.op_number, 1);
+    let reply = cluster.request(1, 5, get_stale(2, 2));
+    assert_eq!(
+      reply.result,
+      OpResult::Rejected(OpError::PreconditionFailed)
+    );
+  }
+
+  #[test]
+  fn backup_restart() {
+    let mut cluster = Cluster::new(3);
+    for n in 1..=5 {
+      cluster.request(0, n, add(n));
+    }
+    let conf = cluster.conf.clone();
+    cluster.replicas[2] = Replica::restart(conf.clone(), 2, cluster.disks[2].replay());
+    cluster.tick(1);
+
+    // The primary keeps going, the restarted backup rejoins its view with the next Prepare.
+    let reply = cluster.request(0, 6, add(6));
+    assert_eq!(reply.result, OpResult::AddResult(Ok(())));
+    assert_eq!(reply.op_number, 6);
+    cluster.tick(conf.timeouts.heartbeat);
+    for replica in &cluster.replicas {
+      assert_eq!(replica.view(), 0);
+      assert_eq!(*replica.status(), Status::Normal);
+      assert_eq!(replica.commit_number(), 6);
+    }
+  }
+
+  #[test]
+  fn checkpoint_survives_full_restart() {
+    let mut cluster = Cluster::new(3);
+    cluster.down.insert(2);
+    for n in 1..=1100 {
+      cluster.request(0, n, add(n));
+    }
+    let checkpoint_ops = cluster.conf.checkpoint_ops;
+    // Replica 2 catches up through the checkpoint of the primary, its log starts after it.
+    cluster.down.clear();
+    cluster.tick(cluster.conf.timeouts.heartbeat);
+    assert_eq!(cluster.replicas[2].commit_number(), 1100);
+    assert_eq!(
+      cluster.replicas[2].log().first_op_number(),
+      checkpoint_ops + 1
+    );
+    // The journals only hold the ops past the checkpoint, and a few View records.
+    for disk in &cluster.disks {
+      assert_eq!(disk.snapshot.as_ref().unwrap().1.op_number, checkpoint_ops);
+      assert!(disk.records.len() < 1100 - checkpoint_ops + 4);
+    }
+
+    let conf = cluster.conf.clone();
+    cluster.replicas = (0..3)
+      .map(|i| Replica::restart(conf.clone(), i, cluster.disks[i].replay()))
+      .collect();
+    cluster.tick(2 * conf.timeouts.view_change);
+    for replica in &cluster.replicas {
+      assert_eq!(*replica.status(), Status::Normal);
+      assert_eq!(replica.commit_number(), 1100);
+    }
+
+    let primary = conf.primary_id(cluster.replicas[0].view());
+    let reply = cluster.request(primary, 1101, add(1101));
+    assert_eq!(reply.op_number, 1101);
+    let get = Operation::Get {
+      key: Bytes::from("k1"),
+    };
+    let reply = cluster.request(primary, 1102, get);
+    assert_eq!(reply.result, OpResult::GetResult(Ok(Bytes::from("v1"))));
   }
 }
@@ -0,0 +1,618 @@
+use std::{
+  collections::{BTreeMap, BTreeSet},
+  fmt,
+};
+
+use bytes::Bytes;
+use log::debug;
+
+use crate::{
+  checkpoint::Checkpoint,
+  configuration::Configuration,
+  error::OpError,
+  journal::{Record, Replayed},
+  linearizability::History,
+  message::{ClientRequest, IOMessage, Reply},
+  operation::{OpResult, Operation},
+  replica::{Destination, JournalWrite, Replica, Status},
+  types::{ClientID, OpNumber, ReplicaID, RequestID, ViewNumber},
+};
+
+// Runs a cluster and its clients in one process, driven by a single seed. Every step is one tick
+// of every replica. Messages go through a simulated network that drops, duplicates, delays and
+// partitions them, and replicas crash and restart, with or without their journal, one at a time
+// or the whole cluster at once. After every step the invariants are checked against what the
+// replicas and clients have seen so far, and at the end the history of client ops must be
+// linearizable.
+//
+// A run ends with a period without faults in which every client must complete a request, so a
+// cluster that stays safe by never making progress is caught too.
+
+/// SplitMix64, the whole run is reproducible from its seed.
+pub struct Prng(u64);
+
+impl Prng {
+  pub fn new(seed: u64) -> Self {
+    Prng(seed)
+  }
+
+  pub fn next_u64(&mut self) -> u64 {
+    self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = self.0;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+  }
+
+  /// Uniform in `0..n`.
+  pub fn below(&mut self, n: u64) -> u64 {
+    self.next_u64() % n
+  }
+
+  /// True with probability `p`.
+  pub fn chance(&mut self, p: f64) -> bool {
+    ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < p
+  }
+}
+
+/// Probabilities are per message or per step.
+#[derive(Clone, Debug)]
+pub struct Options {
+  pub replicas: usize,
+  pub clients: usize,
+  pub steps: u64,        // with faults
+  pub settle_steps: u64, // without faults at the end, every client must complete a request
+  pub drop: f64,
+  pub duplicate: f64,
+  pub delay_max: u64, // ticks in flight, messages overtake each other within it
+  pub partition: f64, // of splitting the replicas in two
+  pub heal: f64,      // of an existing partition going away
+  pub crash: f64,     // of a replica crashing, at most f are down or recovering at a time
+  pub restart: f64,   // of a crashed replica coming back
+  pub disk_loss: f64, // of a crash also losing the journal, the replica then recovers
+  pub cluster_crash: f64, // of every replica crashing at once, keeping their journals
+  pub checkpoint_ops: usize, // small, so runs restart from and transfer checkpoints
+  pub client_timeout: u64,
+}
+
+impl Default for Options {
+  fn default() -> Self {
+    Options {
+      replicas: 3,
+      clients: 4,
+      steps: 20_000,
+      settle_steps: 10_000,
+      drop: 0.01,
+      duplicate: 0.01,
+      delay_max: 20,
+      partition: 0.0005,
+      heal: 0.005,
+      crash: 0.0005,
+      restart: 0.005,
+      disk_loss: 0.3,
+      cluster_crash: 0.0001,
+      checkpoint_ops: 64,
+      client_timeout: 1000,
+    }
+  }
+}
+
+#[derive(Debug)]
+pub struct Violation {
+  pub step: u64,
+  pub message: String,
+}
+
+impl fmt::Display for Violation {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "step {}: {}", self.step, self.message)
+  }
+}
+
+#[derive(Debug, Default)]
+pub struct Summary {
+  pub committed: OpNumber,
+  pub view: ViewNumber,
+  pub requests: usize, // completed by clients
+  pub crashes: usize,
+  pub partitions: usize,
+  pub dropped: usize,
+}
+
+struct Packet {
+  from: Destination,
+  to: Destination,
+  msg: IOMessage,
+}
+
+struct Client {
+  id: ClientID,
+  request_number: RequestID,
+  in_flight: Option<(ClientRequest, u64)>, // along with when it was last sent
+  target: ReplicaID,                       // whom we believe to be the primary
+  completed: usize,
+}
+
+/// What the checks last saw of a replica, committed entries are only compared again after the
+/// replica changed view or status.
+#[derive(Clone, Default)]
+struct Checked {
+  view: ViewNumber,
+  normal: bool,
+  commit: OpNumber,
+}
+
+pub struct Simulator {
+  options: Options,
+  conf: Configuration,
+  prng: Prng,
+  now: u64,
+  faults: bool,
+  replicas: Vec<Option<Replica>>, // None while crashed
+  journals: Vec<Vec<Record>>,     // what survives a crash without disk loss
+  snapshots: Vec<Option<(ViewNumber, Checkpoint)>>, // likewise
+  disk_lost: Vec<bool>,           // restarts through recovery until it completed
+  clients: Vec<Client>,
+  network: BTreeMap<(u64, u64), Packet>, // by delivery time, then by send order
+  sent: u64,
+  partition: Option<BTreeSet<ReplicaID>>, // one side of it
+  committed: BTreeMap<OpNumber, ClientRequest>,
+  acked: BTreeMap<OpNumber, (ClientID, RequestID)>, // writes the clients got a reply for
+  history: History,
+  checked: Vec<Checked>,
+  summary: Summary,
+}
+
+impl Simulator {
+  pub fn new(seed: u64, options: Options) -> Self {
+    let mut conf = Configuration {
+      checkpoint_ops: options.checkpoint_ops,
+      ..Configuration::default()
+    };
+    for i in 0..options.replicas {
+      conf.replicas.push(([10, 0, 0, i as u8], 4000).into());
+    }
+    let replicas = (0..options.replicas)
+      .map(|i| Some(Replica::new(conf.clone(), i)))
+      .collect();
+    let clients = (0..options.clients)
+      .map(|i| Client {
+        id: i as ClientID + 1,
+        request_number: 0,
+        in_flight: None,
+        target: 0,
+        completed: 0,
+      })
+      .collect();
+    Simulator {
+      conf,
+      prng: Prng::new(seed),
+      now: 0,
+      faults: true,
+      replicas,
+      journals: vec![Vec::new(); options.replicas],
+      snapshots: vec![None; options.replicas],
+      disk_lost: vec![false; options.replicas],
+      clients,
+      network: BTreeMap::new(),
+      sent: 0,
+      partition: None,
+      committed: BTreeMap::new(),
+      acked: BTreeMap::new(),
+      history: History::default(),
+      checked: vec![Checked::default(); options.replicas],
+      summary: Summary::default(),
+      options,
+    }
+  }
+
+  pub fn run(mut self) -> Result<Summary, Violation> {
+    for _ in 0..self.options.steps {
+      self.step()?;
+    }
+
+    self.faults = false;
+    self.partition = None;
+    for i in 0..self.options.replicas {
+      if self.replicas[i].is_none() {
+        self.restart(i);
+      }
+    }
+    let completed: Vec<usize> = self.clients.iter().map(|c| c.completed).collect();
+    for _ in 0..self.options.settle_steps {
+      self.step()?;
+    }
+    for (client, before) in self.clients.iter().zip(completed) {
+      if client.completed == before {
+        return Err(self.violation(format!(
+          "client {} made no progress without faults, waiting on {:?}",
+          client.id, client.in_flight
+        )));
+      }
+    }
+
+    if let Err(e) = self.history.check() {
+      return Err(self.violation(e.to_string()));
+    }
+
+    self.summary.committed = self.committed.keys().next_back().copied().unwrap_or(0);
+    self.summary.requests = self.clients.iter().map(|c| c.completed).sum();
+    Ok(self.summary)
+  }
+
+  fn step(&mut self) -> Result<(), Violation> {
+    self.now += 1;
+    if self.faults {
+      self.inject_faults();
+    }
+
+    while let Some(entry) = self.network.first_entry() {
+      if entry.key().0 > self.now {
+        break;
+      }
+      let packet = entry.remove();
+      self.deliver(packet)?;
+    }
+
+    for i in 0..self.options.replicas {
+      if let Some(replica) = &mut self.replicas[i] {
+        replica.tick();
+      }
+      self.flush(i);
+    }
+    self.step_clients();
+    self.check()
+  }
+
+  fn inject_faults(&mut self) {
+    if self.prng.chance(self.options.cluster_crash) {
+      debug!("Crashing every replica");
+      for replica in &mut self.replicas {
+        if replica.take().is_some() {
+          self.summary.crashes += 1;
+        }
+      }
+    }
+
+    let f = (self.options.replicas - 1) / 2;
+    for i in 0..self.options.replicas {
+      match &self.replicas[i] {
+        Some(_) if self.prng.chance(self.options.crash) && self.down() < f => {
+          let disk_loss = self.prng.chance(self.options.disk_loss);
+          debug!("Crashing replica {} (disk loss: {})", i, disk_loss);
+          self.replicas[i] = None;
+          if disk_loss {
+            self.journals[i].clear();
+            self.snapshots[i] = None;
+            self.disk_lost[i] = true;
+          }
+          self.summary.crashes += 1;
+        }
+        None if self.prng.chance(self.options.restart) => self.restart(i),
+        _ => {}
+      }
+    }
+
+    if self.partition.is_some() {
+      if self.prng.chance(self.options.heal) {
+        debug!("Healing partition {:?}", self.partition);
+        self.partition = None;
+      }
+    } else if self.prng.chance(self.options.partition) {
+      let n = self.options.replicas as u64;
+      let side: BTreeSet<ReplicaID> = (0..n)
+        .filter(|_| self.prng.chance(0.5))
+        .map(|i| i as ReplicaID)
+        .collect();
+      if !side.is_empty() && side.len() < self.options.replicas {
+        debug!("Partitioning {:?} from the rest", side);
+        self.partition = Some(side);
+        self.summary.partitions += 1;
+      }
+    }
+  }
+
+  /// Replicas that cannot take part in the protocol: crashed or recovering.
+  fn down(&self) -> usize {
+    self
+      .replicas
+      .iter()
+      .filter(|r| r.as_ref().is_none_or(|r| *r.status() == Status::Recovering))
+      .count()
+  }
+
+  /// Mirrors the startup of a real replica, an operator restarts one that lost its disk with
+  /// `--recover`.
+  fn restart(&mut self, i: ReplicaID) {
+    let mut replayed = Replayed::default();
+    for record in &self.journals[i] {
+      replayed.apply(record.clone());
+    }
+    if let Some((view, checkpoint)) = &self.snapshots[i] {
+      replayed.install_checkpoint(*view, checkpoint.clone());
+    }
+    let replica = if self.disk_lost[i] {
+      Replica::recovering(self.conf.clone(), i, self.prng.next_u64() as u128)
+    } else if !replayed.is_empty() {
+      Replica::restart(self.conf.clone(), i, replayed)
+    } else {
+      Replica::new(self.conf.clone(), i)
+    };
+    debug!("Restarting replica {}", i);
+    self.replicas[i] = Some(replica);
+    self.checked[i] = Checked::default();
+    self.flush(i);
+  }
+
+  fn send(&mut self, from: Destination, to: Destination, msg: IOMessage) {
+    if self.faults && self.prng.chance(self.options.drop) {
+      self.summary.dropped += 1;
+      return;
+    }
+    let copies = if self.faults && self.prng.chance(self.options.duplicate) {
+      2
+    } else {
+      1
+    };
+    for _ in 0..copies {
+      let at = self.now + 1 + self.prng.below(self.options.delay_max);
+      self.sent += 1;
+      let packet = Packet {
+        from,
+        to,
+        msg: msg.clone(),
+      };
+      self.network.insert((at, self.sent), packet);
+    }
+  }
+
+  fn partitioned(&self, from: Destination, to: Destination) -> bool {
+    match (&self.partition, from, to) {
+      (Some(side), Destination::Replica(a), Destination::Replica(b)) => {
+        side.contains(&a) != side.contains(&b)
+      }
+      _ => false,
+    }
+  }
+
+  fn deliver(&mut self, packet: Packet) -> Result<(), Violation> {
+    if self.partitioned(packet.from, packet.to) {
+      self.summary.dropped += 1;
+      return Ok(());
+    }
+    match (packet.to, packet.msg) {
+      (Destination::Replica(i), msg) => {
+        let Some(replica) = &mut self.replicas[i] else {
+          return Ok(());
+        };
+        match msg {
+          IOMessage::Replica(msg) => replica.on_replica_message(msg),
+          IOMessage::Client(req) => replica.on_client_request(req),
+          msg => panic!("{:?} sent to a replica", msg),
+        }
+        self.flush(i);
+        Ok(())
+      }
+      (Destination::Client(client_id), IOMessage::Reply(reply)) => self.on_reply(client_id, reply),
+      (to, msg) => panic!("{:?} sent to {:?}", msg, to),
+    }
+  }
+
+  /// Journal records are durable before any message is sent, as with the message bus.
+  fn flush(&mut self, i: ReplicaID) {
+    let Some(replica) = &mut self.replicas[i] else {
+      return;
+    };
+    if *replica.status() != Status::Recovering {
+      self.disk_lost[i] = false;
+    }
+    while let Some(write) = replica.dequeue_journal_write() {
+      match write {
+        JournalWrite::Record(record) => self.journals[i].push(record),
+        JournalWrite::Checkpoint {
+          view,
+          checkpoint,
+          records,
+          ..
+        } => {
+          self.snapshots[i] = Some((view, checkpoint));
+          self.journals[i] = records;
+        }
+      }
+    }
+    let mut outgoing = Vec::new();
+    while let Some(out) = replica.dequeue_message() {
+      outgoing.push(out);
+    }
+    for (to, msg) in outgoing {
+      self.send(Destination::Replica(i), to, msg);
+    }
+  }
+
+  fn step_clients(&mut self) {
+    for c in 0..self.clients.len() {
+      let client = &self.clients[c];
+      let resend = match &client.in_flight {
+        Some((_, sent_at)) => self.now >= sent_at + self.options.client_timeout,
+        None => false,
+      };
+      if resend {
+        // The replica might be down or no longer the primary, try the next one.
+        let client = &mut self.clients[c];
+        client.target = (client.target + 1) % self.options.replicas;
+        self.send_request(c);
+      } else if client.in_flight.is_none() && self.prng.chance(0.1) {
+        let request = self.random_request(c);
+        self.history.invoke(request.client_id, request.op.clone());
+        let client = &mut self.clients[c];
+        client.request_number += 1;
+        client.in_flight = Some((request, self.now));
+        self.send_request(c);
+      }
+    }
+  }
+
+  fn random_request(&mut self, c: usize) -> ClientRequest {
+    let client = &self.clients[c];
+    let key = Bytes::from(format!("k{}", self.prng.below(4)));
+    let value = Bytes::from(format!("{}.{}", client.id, client.request_number + 1));
+    let op = match self.prng.below(4) {
+      0 => Operation::Add { key, value },
+      1 => Operation::Update { key, value },
+      2 => Operation::Remove { key },
+      _ => Operation::Get { key },
+    };
+    ClientRequest {
+      client_id: client.id,
+      request_number: client.request_number + 1,
+      op,
+    }
+  }
+
+  fn send_request(&mut self, c: usize) {
+    let client = &mut self.clients[c];
+    let Some((request, sent_at)) = &mut client.in_flight else {
+      return;
+    };
+    *sent_at = self.now;
+    let (from, to) = (
+      Destination::Client(client.id),
+      Destination::Replica(client.target),
+    );
+    let msg = IOMessage::Client(request.clone());
+    self.send(from, to, msg);
+  }
+
+  fn on_reply(&mut self, client_id: ClientID, reply: Reply) -> Result<(), Violation> {
+    let c = (client_id - 1) as usize;
+    let client = &mut self.clients[c];
+    let Some((request, _)) = &client.in_flight else {
+      return Ok(());
+    };
+    if reply.request_number != request.request_number {
+      return Ok(());
+    }
+
+    match reply.result {
+      OpResult::Rejected(OpError::NotPrimary) => {
+        client.target = self.conf.primary_id(reply.view_number);
+        self.send_request(c);
+        return Ok(());
+      }
+      // Retried on the timeout.
+      OpResult::Rejected(_) => return Ok(()),
+      _ => {}
+    }
+
+    let request = client.in_flight.take().expect("checked above").0;
+    client.completed += 1;
+    self.history.respond(client_id, reply.result.clone());
+    client.target = self.conf.primary_id(reply.view_number);
+    // Reads served under a lease carry the commit number rather than an op of their own.
+    if matches!(request.op, Operation::Get { .. }) {
+      return Ok(());
+    }
+    let ack = (request.client_id, request.request_number);
+    if let Some(&other) = self.acked.get(&reply.op_number) {
+      if other != ack {
+        return Err(self.violation(format!(
+          "op {} acknowledged to both {:?} and {:?}",
+          reply.op_number, other, ack
+        )));
+      }
+    }
+    self.acked.insert(reply.op_number, ack);
+    self.check_ack(reply.op_number)
+  }
+
+  fn check(&mut self) -> Result<(), Violation> {
+    for i in 0..self.options.replicas {
+      let Some(replica) = &self.replicas[i] else {
+        continue;
+      };
+      let normal = *replica.status() == Status::Normal;
+      let checked = &self.checked[i];
+      let from = if checked.view == replica.view() && checked.normal == normal {
+        checked.commit + 1
+      } else {
+        1
+      };
+      let commit = replica.commit_number();
+      let log = replica.log();
+      for op_number in from.max(log.first_op_number())..=commit.min(log.last_op_number()) {
+        let request = log.get(op_number).expect("in the log");
+        match self.committed.get(&op_number) {
+          Some(other) if other != request => {
+            let message = format!(
+              "replica {} committed {:?} as op {}, which was committed as {:?}",
+              i, request, op_number, other
+            );
+            return Err(self.violation(message));
+          }
+          Some(_) => {}
+          None => {
+            self.committed.insert(op_number, request.clone());
+            self.check_ack(op_number)?;
+          }
+        }
+      }
+      self.checked[i] = Checked {
+        view: replica.view(),
+        normal,
+        commit,
+      };
+    }
+    self.check_primary()
+  }
+
+  /// Once the latest view has started, its primary must have every op committed so far, a view
+  /// change may not lose any of them.
+  fn check_primary(&mut self) -> Result<(), Violation> {
+    let Some(view) = self.replicas.iter().flatten().map(|r| r.view()).max() else {
+      return Ok(());
+    };
+    self.summary.view = self.summary.view.max(view);
+    let primary = self.replicas[self.conf.primary_id(view)]
+      .as_ref()
+      .filter(|r| r.view() == view && *r.status() == Status::Normal);
+    let Some(primary) = primary else {
+      return Ok(());
+    };
+
+    let log = primary.log();
+    for (&op_number, request) in self.committed.range(log.first_op_number()..) {
+      if log.get(op_number) != Some(request) {
+        return Err(self.violation(format!(
+          "primary of view {} has {:?} as op {}, which was committed as {:?}",
+          primary.view(),
+          log.get(op_number),
+          op_number,
+          request
+        )));
+      }
+    }
+    Ok(())
+  }
+
+  /// A client was told its write executed as an op, which must be the op committed there.
+  fn check_ack(&self, op_number: OpNumber) -> Result<(), Violation> {
+    let (Some(ack), Some(request)) = (self.acked.get(&op_number), self.committed.get(&op_number))
+    else {
+      return Ok(());
+    };
+    if *ack != (request.client_id, request.request_number) {
+      return Err(self.violation(format!(
+        "op {} acknowledged to {:?} but committed as {:?}",
+        op_number, ack, request
+      )));
+    }
+    Ok(())
+  }
+
+  fn violation(&self, message: String) -> Violation {
+    Violation {
+      step: self.now,
+      message,
+    }
+  }
+}
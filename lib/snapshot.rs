@@ -0,0 +1,250 @@
+use std::{
+  fs::{self, File},
+  io::{self, BufReader, BufWriter, Error, ErrorKind, Read, Write},
+  path::Path,
+};
+
+use bytes::Bytes;
+
+use crate::{
+  checkpoint::Checkpoint,
+  client_table::{ClienTable, Entry},
+  kvstore::KVStore,
+  types::{ClientID, ViewNumber},
+  utils::{sync_dir, Crc32},
+};
+
+const MAGIC: [u8; 4] = *b"VNSS";
+pub const VERSION: u32 = 1;
+
+// A snapshot is a checkpoint along with the view it was taken in, integers are big endian:
+//
+//   magic, version: u32, view: u64, op_number: u64,
+//   store_len: u64, store_len * (key_len: u32, key, value_len: u32, value),
+//   table_len: u64, table_len * (client_id: u128, entry_len: u32, bincode entry),
+//   crc32 of everything above: u32
+//
+// Both directions stream the store, nothing is buffered beyond a single key or value.
+
+/// Updates a running checksum with every byte that passes through.
+struct Checksummed<T> {
+  inner: T,
+  crc: Crc32,
+}
+
+impl<W: Write> Write for Checksummed<W> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    let n = self.inner.write(buf)?;
+    self.crc.update(&buf[..n]);
+    Ok(n)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.inner.flush()
+  }
+}
+
+impl<R: Read> Read for Checksummed<R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let n = self.inner.read(buf)?;
+    self.crc.update(&buf[..n]);
+    Ok(n)
+  }
+}
+
+pub fn write<W: Write>(w: W, view: ViewNumber, checkpoint: &Checkpoint) -> io::Result<()> {
+  let mut w = Checksummed {
+    inner: BufWriter::new(w),
+    crc: Crc32::default(),
+  };
+  w.write_all(&MAGIC)?;
+  w.write_all(&VERSION.to_be_bytes())?;
+  w.write_all(&(view as u64).to_be_bytes())?;
+  w.write_all(&(checkpoint.op_number as u64).to_be_bytes())?;
+
+  w.write_all(&(checkpoint.store.len() as u64).to_be_bytes())?;
+  for (key, value) in checkpoint.store.iter() {
+    write_bytes(&mut w, key)?;
+    write_bytes(&mut w, value)?;
+  }
+
+  w.write_all(&(checkpoint.client_table.len() as u64).to_be_bytes())?;
+  for (client_id, entry) in checkpoint.client_table.iter() {
+    w.write_all(&client_id.to_be_bytes())?;
+    let buf = bincode::serialize(entry).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    write_bytes(&mut w, &buf)?;
+  }
+
+  let crc = w.crc.finish();
+  w.inner.write_all(&crc.to_be_bytes())?;
+  w.inner.flush()
+}
+
+/// Reads a snapshot written by `write`, failing on anything but an intact snapshot of the
+/// current version.
+pub fn read<R: Read>(r: R) -> io::Result<(ViewNumber, Checkpoint)> {
+  let mut r = Checksummed {
+    inner: BufReader::new(r),
+    crc: Crc32::default(),
+  };
+  let mut magic = [0u8; 4];
+  r.read_exact(&mut magic)?;
+  if magic != MAGIC {
+    return Err(Error::new(ErrorKind::InvalidData, "not a snapshot"));
+  }
+  let version = u32::from_be_bytes(read_array(&mut r)?);
+  if version != VERSION {
+    return Err(Error::new(
+      ErrorKind::InvalidData,
+      format!("unsupported snapshot version {}", version),
+    ));
+  }
+  let view = u64::from_be_bytes(read_array(&mut r)?) as ViewNumber;
+  let op_number = u64::from_be_bytes(read_array(&mut r)?) as usize;
+
+  let store_len = u64::from_be_bytes(read_array(&mut r)?);
+  let store = (0..store_len)
+    .map(|_| Ok((read_bytes(&mut r)?, read_bytes(&mut r)?)))
+    .collect::<io::Result<KVStore>>()?;
+
+  let table_len = u64::from_be_bytes(read_array(&mut r)?);
+  let client_table = (0..table_len)
+    .map(|_| {
+      let client_id = ClientID::from_be_bytes(read_array(&mut r)?);
+      let buf = read_bytes(&mut r)?;
+      let entry: Entry =
+        bincode::deserialize(&buf).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+      Ok((client_id, entry))
+    })
+    .collect::<io::Result<ClienTable>>()?;
+
+  let crc = r.crc.finish();
+  if u32::from_be_bytes(read_array(&mut r.inner)?) != crc {
+    return Err(Error::new(
+      ErrorKind::InvalidData,
+      "snapshot checksum mismatch",
+    ));
+  }
+
+  Ok((
+    view,
+    Checkpoint {
+      op_number,
+      store,
+      client_table,
+    },
+  ))
+}
+
+/// Replaces the snapshot at `path` atomically, a crash leaves either the old or the new one.
+pub fn save(path: &Path, view: ViewNumber, checkpoint: &Checkpoint) -> io::Result<()> {
+  let tmp = path.with_extension("tmp");
+  let file = File::create(&tmp)?;
+  write(&file, view, checkpoint)?;
+  file.sync_all()?;
+  fs::rename(&tmp, path)?;
+  sync_dir(path)
+}
+
+pub fn load(path: &Path) -> io::Result<(ViewNumber, Checkpoint)> {
+  read(File::open(path)?)
+}
+
+fn write_bytes<W: Write>(w: &mut W, bytes: &[u8]) -> io::Result<()> {
+  let len: u32 = bytes
+    .len()
+    .try_into()
+    .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+  w.write_all(&len.to_be_bytes())?;
+  w.write_all(bytes)
+}
+
+fn read_array<R: Read, const N: usize>(r: &mut R) -> io::Result<[u8; N]> {
+  let mut buf = [0u8; N];
+  r.read_exact(&mut buf)?;
+  Ok(buf)
+}
+
+fn read_bytes<R: Read>(r: &mut R) -> io::Result<Bytes> {
+  let len = u32::from_be_bytes(read_array(r)?) as usize;
+  // Grows with the data actually read, a corrupt length fails on EOF rather than allocating.
+  let mut buf = Vec::new();
+  r.take(len as u64).read_to_end(&mut buf)?;
+  if buf.len() != len {
+    return Err(Error::new(ErrorKind::UnexpectedEof, "truncated snapshot"));
+  }
+  Ok(Bytes::from(buf))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::operation::OpResult;
+
+  fn checkpoint() -> Checkpoint {
+    let store = (0..100)
+      .map(|i| {
+        (
+          Bytes::from(format!("k{}", i)),
+          Bytes::from(vec![i as u8; i]),
+        )
+      })
+      .collect();
+    let mut client_table = ClienTable::default();
+    client_table.update_client(7, 3, 99, OpResult::AddResult(Ok(())));
+    Checkpoint {
+      op_number: 4096,
+      store,
+      client_table,
+    }
+  }
+
+  fn written() -> Vec<u8> {
+    let mut buf = Vec::new();
+    write(&mut buf, 5, &checkpoint()).unwrap();
+    buf
+  }
+
+  #[test]
+  fn round_trip() {
+    let (view, read) = read(&written()[..]).unwrap();
+    assert_eq!(view, 5);
+    assert_eq!(read, checkpoint());
+  }
+
+  #[test]
+  fn corrupt() {
+    let buf = written();
+    for i in (0..buf.len()).step_by(11) {
+      let mut buf = buf.clone();
+      buf[i] ^= 1;
+      assert!(read(&buf[..]).is_err(), "flipped a bit of byte {}", i);
+    }
+
+    let buf = written();
+    let err = read(&buf[..buf.len() - 1]).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+  }
+
+  #[test]
+  fn version() {
+    let mut buf = written();
+    buf[4..8].copy_from_slice(&(VERSION + 1).to_be_bytes());
+    let err = read(&buf[..]).unwrap_err();
+    assert!(err.to_string().contains("unsupported snapshot version"));
+
+    buf[..4].copy_from_slice(b"JUNK");
+    assert!(read(&buf[..]).is_err());
+  }
+
+  #[test]
+  fn save_and_load() {
+    let path = std::env::temp_dir().join(format!("save-{}.snapshot", std::process::id()));
+    save(&path, 5, &checkpoint()).unwrap();
+    let mut other = checkpoint();
+    other.op_number += 1;
+    save(&path, 6, &other).unwrap();
+    assert_eq!(load(&path).unwrap(), (6, other));
+    fs::remove_file(&path).unwrap();
+  }
+}
@@ -1,7 +1,10 @@
 pub type ClientID = u128;
+pub type ClusterID = u128;
 pub type CommitID = usize;
 pub type OpNumber = usize;
 pub type ReplicaID = usize;
 pub type RequestID = usize;
 pub type ViewNumber = usize;
 pub type ConnectionID = usize;
+pub type Nonce = u128;
+pub type Timestamp = usize; // in ticks of the sending replica
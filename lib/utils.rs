@@ -1,2 +1,46 @@
+use std::{fs::File, io, path::Path};
+
 /// For explicitness
 pub fn do_nothing() {}
+
+/// Makes a rename or creation of `path` durable by syncing the directory it is in.
+pub fn sync_dir(path: &Path) -> io::Result<()> {
+  let dir = path
+    .parent()
+    .filter(|dir| !dir.as_os_str().is_empty())
+    .unwrap_or(Path::new("."));
+  File::open(dir)?.sync_all()
+}
+
+/// CRC-32 (IEEE), guards on-disk records against torn writes and bit rot.
+pub fn crc32(data: &[u8]) -> u32 {
+  let mut crc = Crc32::default();
+  crc.update(data);
+  crc.finish()
+}
+
+/// CRC-32 computed over data that arrives in pieces.
+#[derive(Clone, Copy, Debug)]
+pub struct Crc32(u32);
+
+impl Default for Crc32 {
+  fn default() -> Self {
+    Crc32(!0)
+  }
+}
+
+impl Crc32 {
+  pub fn update(&mut self, data: &[u8]) {
+    for byte in data {
+      self.0 ^= *byte as u32;
+      for _ in 0..8 {
+        let mask = (self.0 & 1).wrapping_neg();
+        self.0 = (self.0 >> 1) ^ (0xEDB8_8320 & mask);
+      }
+    }
+  }
+
+  pub fn finish(&self) -> u32 {
+    !self.0
+  }
+}